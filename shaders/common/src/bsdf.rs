@@ -0,0 +1,393 @@
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+
+use core::f32::consts::FRAC_1_PI;
+use spirv_std::glam::*;
+
+use crate::fresnel::{fresnel_dielectric, fresnel_schlick, reflect, refract};
+use crate::microfacet::GGX;
+use crate::warp;
+
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy)]
+pub struct BsdfSample {
+    pub wo: Vec3,
+    pub pdf: f32,
+}
+
+impl BsdfSample {
+    pub fn invalid() -> Self {
+        Self {
+            wo: Vec3::ZERO,
+            pdf: 0.,
+        }
+    }
+}
+
+///
+/// BSDF of the glTF metallic-roughness material.
+///
+/// It consists of a GGX specular lobe, blending between a dielectric and a metallic Fresnel
+/// term, a Lambertian diffuse lobe and a rough dielectric transmission lobe weighted by the
+/// transmission factor.
+/// All directions are given in the local shading frame, `wi` points towards the previous vertex
+/// and `wo` towards the next one.
+///
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy)]
+pub struct PbrBsdf {
+    pub base_color: Vec3,
+    pub metallic: f32,
+    pub transmission: f32,
+    pub eta: f32,
+    pub distr: GGX,
+}
+
+impl PbrBsdf {
+    pub fn new(
+        base_color: Vec3,
+        metallic: f32,
+        roughness: f32,
+        transmission: f32,
+        eta: f32,
+    ) -> Self {
+        Self {
+            base_color,
+            metallic: metallic.clamp(0., 1.),
+            transmission: transmission.clamp(0., 1.),
+            eta,
+            distr: GGX::new(roughness * roughness),
+        }
+    }
+
+    ///
+    /// Probabilities of choosing the specular, diffuse and transmission lobe when sampling.
+    ///
+    fn lobe_probabilities(&self, cos_theta_i: f32) -> (f32, f32, f32) {
+        let f = fresnel_dielectric(cos_theta_i, self.eta);
+        let dielectric = 1. - self.metallic;
+        let specular = self.metallic + dielectric * f;
+        let diffuse = dielectric * (1. - self.transmission) * (1. - f);
+        let transmission = dielectric * self.transmission * (1. - f);
+        (specular, diffuse, transmission)
+    }
+
+    ///
+    /// Evaluates the BSDF times the foreshortening term `|cos(theta_o)|`.
+    /// Reflection is reciprocal, transmission scales the radiance by `1 / eta^2`.
+    ///
+    pub fn eval(&self, wi: Vec3, wo: Vec3) -> Vec3 {
+        let cos_theta_i = wi.z;
+        let cos_theta_o = wo.z;
+        if cos_theta_i == 0. || cos_theta_o == 0. {
+            return Vec3::ZERO;
+        }
+
+        if cos_theta_i * cos_theta_o > 0. {
+            // Reflection
+            let wm = wi + wo;
+            if wm.length_squared() == 0. {
+                return Vec3::ZERO;
+            }
+            let wm = wm.normalize();
+            let wm = if wm.z < 0. { -wm } else { wm };
+
+            let f_dielectric = fresnel_dielectric(wi.dot(wm), self.eta);
+            let f_metal = fresnel_schlick(self.base_color, wi.dot(wm).abs());
+            let f = f_metal * self.metallic + Vec3::splat((1. - self.metallic) * f_dielectric);
+
+            let specular = f
+                * (self.distr.d(wm) * self.distr.g(wi, wo)
+                    / (4. * cos_theta_i.abs() * cos_theta_o.abs()));
+
+            // Light is refracted into and out of the diffuse base, which keeps it reciprocal
+            let diffuse = self.base_color
+                * ((1. - self.metallic)
+                    * (1. - self.transmission)
+                    * (1. - fresnel_dielectric(cos_theta_i, self.eta))
+                    * (1. - fresnel_dielectric(cos_theta_o, self.eta))
+                    * FRAC_1_PI);
+
+            (specular + diffuse) * cos_theta_o.abs()
+        } else {
+            // Transmission
+            if self.transmission == 0. || self.metallic == 1. {
+                return Vec3::ZERO;
+            }
+            let etap = if cos_theta_i > 0. {
+                self.eta
+            } else {
+                1. / self.eta
+            };
+            let wm = wo * etap + wi;
+            if wm.length_squared() == 0. {
+                return Vec3::ZERO;
+            }
+            let wm = wm.normalize();
+            let wm = if wm.z < 0. { -wm } else { wm };
+
+            // Discard back-facing microfacets
+            if wm.dot(wi) * cos_theta_i < 0. || wm.dot(wo) * cos_theta_o < 0. {
+                return Vec3::ZERO;
+            }
+
+            let denom = wo.dot(wm) + wi.dot(wm) / etap;
+            let denom = denom * denom * cos_theta_i * cos_theta_o;
+
+            let t = (1. - self.metallic)
+                * self.transmission
+                * (1. - fresnel_dielectric(wi.dot(wm), self.eta))
+                * self.distr.d(wm)
+                * self.distr.g(wi, wo)
+                * (wo.dot(wm) * wi.dot(wm) / denom).abs()
+                / (etap * etap);
+
+            self.base_color * (t * cos_theta_o.abs())
+        }
+    }
+
+    pub fn pdf(&self, wi: Vec3, wo: Vec3) -> f32 {
+        let cos_theta_i = wi.z;
+        let cos_theta_o = wo.z;
+        if cos_theta_i == 0. || cos_theta_o == 0. {
+            return 0.;
+        }
+        let (p_specular, p_diffuse, p_transmission) = self.lobe_probabilities(cos_theta_i);
+
+        if cos_theta_i * cos_theta_o > 0. {
+            let wm = wi + wo;
+            if wm.length_squared() == 0. {
+                return 0.;
+            }
+            let wm = wm.normalize();
+            let wm = if wm.z < 0. { -wm } else { wm };
+
+            let specular = if wm.dot(wi) * cos_theta_i < 0. || wm.dot(wo) * cos_theta_o < 0. {
+                0.
+            } else {
+                self.distr.d_visible(wi, wm) / (4. * wi.dot(wm).abs())
+            };
+            let diffuse = warp::square_to_cosine_hemisphere_pdf(vec3(wo.x, wo.y, wo.z.abs()));
+
+            p_specular * specular + p_diffuse * diffuse
+        } else {
+            if p_transmission == 0. {
+                return 0.;
+            }
+            let etap = if cos_theta_i > 0. {
+                self.eta
+            } else {
+                1. / self.eta
+            };
+            let wm = wo * etap + wi;
+            if wm.length_squared() == 0. {
+                return 0.;
+            }
+            let wm = wm.normalize();
+            let wm = if wm.z < 0. { -wm } else { wm };
+
+            if wm.dot(wi) * cos_theta_i < 0. || wm.dot(wo) * cos_theta_o < 0. {
+                return 0.;
+            }
+
+            let denom = wo.dot(wm) + wi.dot(wm) / etap;
+            let dwm_dwo = wo.dot(wm).abs() / (denom * denom);
+
+            p_transmission * self.distr.d_visible(wi, wm) * dwm_dwo
+        }
+    }
+
+    ///
+    /// Samples an outgoing direction and returns it together with the weight
+    /// `eval(wi, wo) / pdf(wi, wo)`.
+    ///
+    pub fn sample(&self, wi: Vec3, sample1: f32, sample2: Vec2) -> (BsdfSample, Vec3) {
+        let cos_theta_i = wi.z;
+        if cos_theta_i == 0. {
+            return (BsdfSample::invalid(), Vec3::ZERO);
+        }
+        let (p_specular, p_diffuse, _) = self.lobe_probabilities(cos_theta_i);
+
+        // Directions ending up on the side of the other lobe type are rejected, as `pdf` does
+        // not account for them
+        let wo = if sample1 < p_specular {
+            let wm = self.distr.sample_visible(wi, sample2);
+            let wo = reflect(wi, wm);
+            if wo.z * cos_theta_i <= 0. {
+                return (BsdfSample::invalid(), Vec3::ZERO);
+            }
+            wo
+        } else if sample1 < p_specular + p_diffuse {
+            let wo = warp::square_to_cosine_hemisphere(sample2);
+            if cos_theta_i < 0. {
+                vec3(wo.x, wo.y, -wo.z)
+            } else {
+                wo
+            }
+        } else {
+            let wm = self.distr.sample_visible(wi, sample2);
+            let (valid, wo) = refract(wi, wm, self.eta);
+            if !valid || wo.z * cos_theta_i >= 0. {
+                return (BsdfSample::invalid(), Vec3::ZERO);
+            }
+            wo
+        };
+
+        let pdf = self.pdf(wi, wo);
+        if pdf <= 0. {
+            return (BsdfSample::invalid(), Vec3::ZERO);
+        }
+
+        (BsdfSample { wo, pdf }, self.eval(wi, wo) / pdf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rand::Rng;
+    use core::f32::consts::{PI, TAU};
+
+    fn bsdfs() -> [PbrBsdf; 5] {
+        [
+            PbrBsdf::new(Vec3::ONE, 0., 0.5, 0., 1.5),
+            PbrBsdf::new(Vec3::ONE, 1., 0.6, 0., 1.5),
+            PbrBsdf::new(Vec3::ONE, 0., 0.7, 1., 1.5),
+            PbrBsdf::new(vec3(0.8, 0.5, 0.2), 0.5, 0.8, 0., 1.33),
+            PbrBsdf::new(vec3(0.9, 0.9, 0.7), 0.2, 0.6, 0.5, 1.5),
+        ]
+    }
+
+    fn incident_directions() -> [Vec3; 3] {
+        [
+            vec3(0., 0., 1.),
+            vec3(0.5, 0.1, 0.8).normalize(),
+            vec3(-0.9, 0.3, 0.2).normalize(),
+        ]
+    }
+
+    fn uniform_sphere(sample: Vec2) -> Vec3 {
+        let z = 1. - 2. * sample.x;
+        let r = (1. - z * z).max(0.).sqrt();
+        let phi = TAU * sample.y;
+        vec3(r * phi.cos(), r * phi.sin(), z)
+    }
+
+    fn next_2d(rng: &mut Rng) -> Vec2 {
+        vec2(rng.next_f32(), rng.next_f32())
+    }
+
+    ///
+    /// Bin of a direction in a grid over `cos(theta)` and `phi`.
+    ///
+    fn bin(w: Vec3, theta_bins: usize, phi_bins: usize) -> usize {
+        let u = ((w.z + 1.) * 0.5 * theta_bins as f32) as usize;
+        let phi = w.y.atan2(w.x) + PI;
+        let v = (phi / TAU * phi_bins as f32) as usize;
+        u.min(theta_bins - 1) * phi_bins + v.min(phi_bins - 1)
+    }
+
+    #[test]
+    fn reflection_is_reciprocal() {
+        let mut rng = Rng::new(1);
+        for bsdf in bsdfs() {
+            for _ in 0..1000 {
+                let wi = uniform_sphere(next_2d(&mut rng));
+                let wo = uniform_sphere(next_2d(&mut rng));
+                if wi.z * wo.z <= 1e-3 {
+                    continue;
+                }
+                let f_io = bsdf.eval(wi, wo) / wo.z.abs();
+                let f_oi = bsdf.eval(wo, wi) / wi.z.abs();
+                let tolerance = 1e-3 * f_io.max_element().max(1.);
+                assert!(
+                    (f_io - f_oi).abs().max_element() <= tolerance,
+                    "{:?} for {:?} and {:?}: {:?} != {:?}",
+                    bsdf,
+                    wi,
+                    wo,
+                    f_io,
+                    f_oi
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pdf_matches_sample_density() {
+        const THETA_BINS: usize = 8;
+        const PHI_BINS: usize = 16;
+        const SAMPLES: usize = 400_000;
+
+        let mut rng = Rng::new(2);
+        for bsdf in bsdfs() {
+            for wi in incident_directions() {
+                let mut sampled = [0.; THETA_BINS * PHI_BINS];
+                for _ in 0..SAMPLES {
+                    let (bs, _) = bsdf.sample(wi, rng.next_f32(), next_2d(&mut rng));
+                    if bs.pdf > 0. {
+                        assert!((bs.pdf - bsdf.pdf(wi, bs.wo)).abs() <= 1e-3 * bs.pdf.max(1.));
+                        sampled[bin(bs.wo, THETA_BINS, PHI_BINS)] += 1. / SAMPLES as f32;
+                    }
+                }
+
+                // Integrate the pdf over every bin with the midpoint rule, the bins are uniform
+                // in the solid angle measure `d(cos(theta)) d(phi)`
+                let mut integrated = [0.; THETA_BINS * PHI_BINS];
+                let (nz, nphi) = (THETA_BINS * 64, PHI_BINS * 64);
+                let dw = (2. / nz as f32) * (TAU / nphi as f32);
+                for i in 0..nz {
+                    for j in 0..nphi {
+                        let z = -1. + (i as f32 + 0.5) * 2. / nz as f32;
+                        let phi = -PI + (j as f32 + 0.5) * TAU / nphi as f32;
+                        let r = (1. - z * z).max(0.).sqrt();
+                        let wo = vec3(r * phi.cos(), r * phi.sin(), z);
+                        integrated[bin(wo, THETA_BINS, PHI_BINS)] += bsdf.pdf(wi, wo) * dw;
+                    }
+                }
+
+                for (i, (sampled, integrated)) in sampled.iter().zip(integrated).enumerate() {
+                    assert!(
+                        (sampled - integrated).abs() < 0.005,
+                        "{:?} for {:?}, bin {}: sampled {} but pdf integrates to {}",
+                        bsdf,
+                        wi,
+                        i,
+                        sampled,
+                        integrated
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn white_furnace_does_not_gain_energy() {
+        const SAMPLES: usize = 200_000;
+
+        let mut rng = Rng::new(3);
+        for bsdf in bsdfs() {
+            let bsdf = PbrBsdf {
+                base_color: Vec3::ONE,
+                ..bsdf
+            };
+            // Transmission from the inside scales up the radiance, so only the outside is tested
+            for wi in incident_directions() {
+                let mut albedo = Vec3::ZERO;
+                for _ in 0..SAMPLES {
+                    let (bs, weight) = bsdf.sample(wi, rng.next_f32(), next_2d(&mut rng));
+                    if bs.pdf > 0. {
+                        albedo += weight / SAMPLES as f32;
+                    }
+                }
+                assert!(
+                    albedo.max_element() <= 1.01,
+                    "{:?} reflects {:?} for {:?}",
+                    bsdf,
+                    albedo,
+                    wi
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,437 @@
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+
+use spirv_std::glam::*;
+
+use crate::{Emitter, Instance, Mesh, SurfaceInteraction, TextureSource};
+
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy)]
+pub struct EmitterSample {
+    pub p: Vec3,
+    pub n: Vec3,
+    pub d: Vec3, // Direction from the reference point towards `p`
+    pub dist: f32,
+    pub pdf: f32, // Solid angle pdf
+}
+
+impl EmitterSample {
+    pub fn invalid() -> Self {
+        Self {
+            p: Vec3::ZERO,
+            n: Vec3::ZERO,
+            d: Vec3::ZERO,
+            dist: 0.,
+            pdf: 0.,
+        }
+    }
+}
+
+///
+/// Selects one of the emitters uniformly.
+/// Returns its index, the selection probability and the reusable remainder of `sample`.
+///
+pub fn select_emitter(emitters: &[Emitter], sample: f32) -> (u32, f32, f32) {
+    let count = emitters.len() as u32;
+    let scaled = sample * count as f32;
+    let idx = (scaled as u32).min(count - 1);
+    (idx, 1. / count as f32, scaled - idx as f32)
+}
+
+///
+/// Samples the discrete distribution given by the `count` entries of the normalized cdf at
+/// `offset`. Returns the sampled index and the reusable remainder of `sample`.
+///
+pub fn sample_cdf(cdf: &[f32], offset: u32, count: u32, sample: f32) -> (u32, f32) {
+    let mut lo = 0;
+    let mut hi = count - 1;
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if cdf[(offset + mid) as usize] <= sample {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    let lower = if lo == 0 {
+        0.
+    } else {
+        cdf[(offset + lo - 1) as usize]
+    };
+    let upper = cdf[(offset + lo) as usize];
+    let remainder = if upper > lower {
+        ((sample - lower) / (upper - lower)).min(1.)
+    } else {
+        0.
+    };
+    (lo, remainder)
+}
+
+impl Emitter {
+    pub fn is_area(&self) -> bool {
+        self.ty == Self::TY_AREA
+    }
+    ///
+    /// World space vertices of the triangle `primitive` of the emitter's mesh.
+    ///
+    fn triangle(
+        &self,
+        primitive: u32,
+        instances: &[Instance],
+        meshes: &[Mesh],
+        indices: &[u32],
+        positions: &[Vec3],
+    ) -> (Vec3, Vec3, Vec3) {
+        let instance = instances[self.instance as usize];
+        let mesh = meshes[instance.mesh as usize];
+
+        let idx = mesh.indices as usize + 3 * primitive as usize;
+        let p0 = positions[mesh.positions as usize + indices[idx] as usize];
+        let p1 = positions[mesh.positions as usize + indices[idx + 1] as usize];
+        let p2 = positions[mesh.positions as usize + indices[idx + 2] as usize];
+        (
+            (instance.to_world * p0.extend(1.)).xyz(),
+            (instance.to_world * p1.extend(1.)).xyz(),
+            (instance.to_world * p2.extend(1.)).xyz(),
+        )
+    }
+    ///
+    /// Radiance emitted from the point `si` on the emitter's mesh towards the origin of the ray
+    /// that hit it.
+    /// Emitters are one-sided, only the side the geometric normal given by the winding of the
+    /// triangles points to emits.
+    ///
+    pub fn eval(
+        &self,
+        si: &SurfaceInteraction,
+        instances: &[Instance],
+        meshes: &[Mesh],
+        indices: &[u32],
+        positions: &[Vec3],
+        textures: &impl TextureSource,
+    ) -> Vec3 {
+        if !self.is_area() {
+            return Vec3::ZERO;
+        }
+        let (p0, p1, p2) = self.triangle(si.primitive, instances, meshes, indices, positions);
+        if (p1 - p0).cross(p2 - p0).dot(si.wi.xyz()) <= 0. {
+            return Vec3::ZERO;
+        }
+        self.irradiance.eval(si.uv, textures)
+    }
+    ///
+    /// Samples a point on the emitter's mesh uniformly by area, as seen from `p`.
+    /// Returns the sample and the emitted radiance divided by the solid angle pdf.
+    ///
+    pub fn sample_direction(
+        &self,
+        p: Vec3,
+        sample1: f32,
+        sample2: Vec2,
+        instances: &[Instance],
+        meshes: &[Mesh],
+        indices: &[u32],
+        positions: &[Vec3],
+        uvs: &[Vec2],
+        emitter_cdfs: &[f32],
+        textures: &impl TextureSource,
+    ) -> (EmitterSample, Vec3) {
+        if !self.is_area() || self.area <= 0. {
+            return (EmitterSample::invalid(), Vec3::ZERO);
+        }
+        let mesh = meshes[instances[self.instance as usize].mesh as usize];
+
+        let triangle_count = mesh.indices_count / 3;
+        let (primitive, _) = sample_cdf(emitter_cdfs, self.cdf, triangle_count, sample1);
+        let (p0, p1, p2) = self.triangle(primitive, instances, meshes, indices, positions);
+
+        // Uniformly sample the triangle
+        let su = sample2.x.sqrt();
+        let b = vec2(1. - su, sample2.y * su);
+        let w = vec3(1. - b.x - b.y, b.x, b.y);
+        let sp = p0 * w.x + p1 * w.y + p2 * w.z;
+
+        let n = (p1 - p0).cross(p2 - p0).normalize();
+
+        let d = sp - p;
+        let dist2 = d.length_squared();
+        let dist = dist2.sqrt();
+        let d = d / dist;
+
+        // Emitters are one-sided
+        let cos_theta = n.dot(-d);
+        if cos_theta <= 0. {
+            return (EmitterSample::invalid(), Vec3::ZERO);
+        }
+
+        let pdf = dist2 / (cos_theta * self.area);
+
+        let idx = mesh.indices as usize + 3 * primitive as usize;
+        let uv = uvs[mesh.uvs as usize + indices[idx] as usize] * w.x
+            + uvs[mesh.uvs as usize + indices[idx + 1] as usize] * w.y
+            + uvs[mesh.uvs as usize + indices[idx + 2] as usize] * w.z;
+
+        (
+            EmitterSample {
+                p: sp,
+                n,
+                d,
+                dist,
+                pdf,
+            },
+            self.irradiance.eval(uv, textures) / pdf,
+        )
+    }
+    ///
+    /// Solid angle pdf of sampling the point `si` on this emitter with `sample_direction`,
+    /// as seen from the origin of the ray that hit it.
+    ///
+    pub fn pdf_direction(
+        &self,
+        si: &SurfaceInteraction,
+        instances: &[Instance],
+        meshes: &[Mesh],
+        indices: &[u32],
+        positions: &[Vec3],
+    ) -> f32 {
+        if !self.is_area() || self.area <= 0. {
+            return 0.;
+        }
+        let (p0, p1, p2) = self.triangle(si.primitive, instances, meshes, indices, positions);
+
+        let cos_theta = (p1 - p0).cross(p2 - p0).normalize().dot(si.wi.xyz());
+        if cos_theta <= 0. {
+            return 0.;
+        }
+
+        si.dist * si.dist / (cos_theta * self.area)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Texture;
+
+    #[test]
+    fn sample_cdf_is_proportional_to_weights() {
+        // Two cdfs in one buffer, the second one has a zero weight entry
+        let weights = [[1., 3., 4., 2.], [2., 0., 5., 3.]];
+        let mut cdf = vec![];
+        for w in weights {
+            let total: f32 = w.iter().sum();
+            let mut sum = 0.;
+            for w in w {
+                sum += w;
+                cdf.push(sum / total);
+            }
+        }
+
+        const SAMPLES: u32 = 10000;
+        for (i, w) in weights.iter().enumerate() {
+            let offset = 4 * i as u32;
+            let mut counts = [0; 4];
+            for s in 0..SAMPLES {
+                let sample = (s as f32 + 0.5) / SAMPLES as f32;
+                let (idx, remainder) = sample_cdf(&cdf, offset, 4, sample);
+                assert!((0. ..=1.).contains(&remainder));
+                counts[idx as usize] += 1;
+            }
+            let total: f32 = w.iter().sum();
+            for (count, w) in counts.iter().zip(w) {
+                let expected = w / total * SAMPLES as f32;
+                assert!((*count as f32 - expected).abs() <= 1., "{:?}", counts);
+            }
+        }
+    }
+
+    #[test]
+    fn sample_cdf_single_entry() {
+        let cdf = [1.];
+        for sample in [0., 0.5, 0.999] {
+            let (idx, remainder) = sample_cdf(&cdf, 0, 1, sample);
+            assert_eq!(idx, 0);
+            assert!((remainder - sample).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn select_emitter_is_uniform() {
+        let emitters = [Emitter::area(Default::default(), 0); 3];
+        for (sample, expected) in [(0., 0), (0.5, 1), (0.99, 2)] {
+            let (idx, pdf, remainder) = select_emitter(&emitters, sample);
+            assert_eq!(idx, expected);
+            assert!((pdf - 1. / 3.).abs() < 1e-6);
+            assert!((0. ..1.).contains(&remainder));
+        }
+    }
+
+    ///
+    /// Square emitter of half-size 1 at height 1, facing down, with uvs spanning it.
+    ///
+    struct SquareLight {
+        emitter: Emitter,
+        instances: [Instance; 1],
+        meshes: [Mesh; 1],
+        indices: [u32; 6],
+        positions: [Vec3; 4],
+        uvs: [Vec2; 4],
+        cdfs: [f32; 2],
+    }
+
+    impl SquareLight {
+        fn new(irradiance: Texture) -> Self {
+            let positions = [
+                vec3(-1., -1., 1.),
+                vec3(1., -1., 1.),
+                vec3(1., 1., 1.),
+                vec3(-1., 1., 1.),
+            ];
+            Self {
+                emitter: Emitter {
+                    area: 4.,
+                    ..Emitter::area(irradiance, 0)
+                },
+                instances: [Instance {
+                    to_world: Mat4::IDENTITY,
+                    mesh: 0,
+                    material: 0,
+                    emitter: 0,
+                }],
+                meshes: [Mesh {
+                    indices: 0,
+                    indices_count: 6,
+                    positions: 0,
+                    normals: 0,
+                    uvs: 0,
+                }],
+                indices: [0, 2, 1, 0, 3, 2],
+                uvs: positions.map(|p| (p.xy() + Vec2::ONE) * 0.5),
+                positions,
+                cdfs: [0.5, 1.],
+            }
+        }
+        fn sample_direction(&self, p: Vec3, sample1: f32, sample2: Vec2) -> (EmitterSample, Vec3) {
+            self.emitter.sample_direction(
+                p,
+                sample1,
+                sample2,
+                &self.instances,
+                &self.meshes,
+                &self.indices,
+                &self.positions,
+                &self.uvs,
+                &self.cdfs,
+                &UvTexture,
+            )
+        }
+    }
+
+    /// Texture with the uv coordinates as its color.
+    struct UvTexture;
+
+    impl TextureSource for UvTexture {
+        fn sample(&self, _texture: u32, uv: Vec2) -> Vec4 {
+            uv.extend(0.).extend(1.)
+        }
+    }
+
+    fn samples(n: u32) -> impl Iterator<Item = (f32, Vec2)> {
+        (0..n * n * n).map(move |i| {
+            let stratum = |i: u32| (i as f32 + 0.5) / n as f32;
+            (
+                stratum(i % n),
+                vec2(stratum(i / n % n), stratum(i / (n * n))),
+            )
+        })
+    }
+
+    #[test]
+    fn sample_direction_estimates_irradiance() {
+        // Irradiance below the center of a square light of radiance `L`, see the closed form of
+        // the radiance of a Lambertian floor in the tests of the reference integrator
+        const L: Vec3 = vec3(1., 2., 4.);
+        let light = SquareLight::new(Texture::constant(L));
+        let s = 1. / 2f32.sqrt();
+        let expected = L * 4. * s * s.atan();
+
+        let n = 32;
+        let mut sum = Vec3::ZERO;
+        for (sample1, sample2) in samples(n) {
+            let (ds, weight) = light.sample_direction(Vec3::ZERO, sample1, sample2);
+            assert!(ds.pdf > 0.);
+            sum += weight * ds.d.z;
+        }
+        let estimate = sum / (n * n * n) as f32;
+        assert!(
+            ((estimate - expected).abs() / expected).max_element() < 0.01,
+            "{estimate} != {expected}"
+        );
+    }
+
+    #[test]
+    fn emitters_are_one_sided() {
+        let light = SquareLight::new(Texture::constant(Vec3::ONE));
+        for (sample1, sample2) in samples(4) {
+            let (ds, weight) = light.sample_direction(vec3(0., 0., 2.), sample1, sample2);
+            assert_eq!(ds.pdf, 0.);
+            assert_eq!(weight, Vec3::ZERO);
+        }
+
+        let si = |wi: Vec3| SurfaceInteraction {
+            p: vec4(0., 0., 1., 1.),
+            n: Vec4::Z,
+            wi: wi.extend(0.),
+            uv: vec2(0.5, 0.5),
+            dist: 1.,
+            t: 0.,
+            instance: 0,
+            primitive: 0,
+            material: 0,
+        };
+        let eval = |wi| {
+            light.emitter.eval(
+                &si(wi),
+                &light.instances,
+                &light.meshes,
+                &light.indices,
+                &light.positions,
+                &UvTexture,
+            )
+        };
+        // The shading normal does not matter, only the winding of the triangle
+        assert_eq!(eval(Vec3::NEG_Z), Vec3::ONE);
+        assert_eq!(eval(Vec3::Z), Vec3::ZERO);
+    }
+
+    #[test]
+    fn textured_emitters_are_evaluated_at_the_uv() {
+        let light = SquareLight::new(Texture::image(0));
+        for (sample1, sample2) in samples(4) {
+            let (ds, weight) = light.sample_direction(Vec3::ZERO, sample1, sample2);
+            let uv = (ds.p.xy() + Vec2::ONE) * 0.5;
+            assert!((weight * ds.pdf).abs_diff_eq(uv.extend(0.), 1e-5));
+        }
+
+        let si = SurfaceInteraction {
+            p: vec4(0.5, -0.5, 1., 1.),
+            n: Vec4::Z,
+            wi: Vec4::NEG_Z,
+            uv: vec2(0.75, 0.25),
+            dist: 1.,
+            t: 0.,
+            instance: 0,
+            primitive: 0,
+            material: 0,
+        };
+        let radiance = light.emitter.eval(
+            &si,
+            &light.instances,
+            &light.meshes,
+            &light.indices,
+            &light.positions,
+            &UvTexture,
+        );
+        assert_eq!(radiance, vec3(0.75, 0.25, 0.));
+    }
+}
@@ -0,0 +1,35 @@
+use spirv_std::glam::*;
+
+///
+/// Orthonormal shading frame, with the normal as local z axis.
+///
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy)]
+pub struct Frame {
+    pub s: Vec3,
+    pub t: Vec3,
+    pub n: Vec3,
+}
+
+impl Frame {
+    ///
+    /// Builds a frame around `n` ("Building an Orthonormal Basis, Revisited", Duff et al. 2017).
+    ///
+    pub fn from_normal(n: Vec3) -> Self {
+        let sign = if n.z >= 0. { 1. } else { -1. };
+        let a = -1. / (sign + n.z);
+        let b = n.x * n.y * a;
+        let s = vec3(1. + sign * n.x * n.x * a, sign * b, -sign * n.x);
+        let t = vec3(b, sign + n.y * n.y * a, -n.y);
+        Self { s, t, n }
+    }
+    pub fn to_local(&self, v: Vec3) -> Vec3 {
+        vec3(v.dot(self.s), v.dot(self.t), v.dot(self.n))
+    }
+    pub fn to_world(&self, v: Vec3) -> Vec3 {
+        self.s * v.x + self.t * v.y + self.n * v.z
+    }
+    pub fn cos_theta(v: Vec3) -> f32 {
+        v.z
+    }
+}
@@ -0,0 +1,105 @@
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+
+use spirv_std::glam::*;
+
+///
+/// Unpolarized Fresnel reflectance of a dielectric interface with relative index of refraction
+/// `eta`. A negative `cos_theta_i` denotes incidence from the inside.
+///
+pub fn fresnel_dielectric(cos_theta_i: f32, eta: f32) -> f32 {
+    let (cos_theta_i, eta) = if cos_theta_i < 0. {
+        (-cos_theta_i, 1. / eta)
+    } else {
+        (cos_theta_i, eta)
+    };
+    let cos_theta_i = cos_theta_i.min(1.);
+
+    let sin2_theta_t = (1. - cos_theta_i * cos_theta_i) / (eta * eta);
+    if sin2_theta_t >= 1. {
+        // Total internal reflection
+        return 1.;
+    }
+    let cos_theta_t = (1. - sin2_theta_t).sqrt();
+
+    let r_parl = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let r_perp = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+    (r_parl * r_parl + r_perp * r_perp) * 0.5
+}
+
+pub fn fresnel_schlick(f0: Vec3, cos_theta: f32) -> Vec3 {
+    let m = (1. - cos_theta).clamp(0., 1.);
+    let m2 = m * m;
+    f0 + (Vec3::ONE - f0) * (m2 * m2 * m)
+}
+
+pub fn reflect(wi: Vec3, m: Vec3) -> Vec3 {
+    -wi + m * (2. * wi.dot(m))
+}
+
+///
+/// Refracts `wi` at a surface with normal `m` and relative index of refraction `eta`.
+/// Returns `false` on total internal reflection.
+///
+pub fn refract(wi: Vec3, m: Vec3, eta: f32) -> (bool, Vec3) {
+    let cos_theta_i = wi.dot(m);
+    let (cos_theta_i, eta, m) = if cos_theta_i < 0. {
+        (-cos_theta_i, 1. / eta, -m)
+    } else {
+        (cos_theta_i, eta, m)
+    };
+
+    let sin2_theta_t = (1. - cos_theta_i * cos_theta_i).max(0.) / (eta * eta);
+    if sin2_theta_t >= 1. {
+        return (false, Vec3::ZERO);
+    }
+    let cos_theta_t = (1. - sin2_theta_t).sqrt();
+
+    (true, -wi / eta + m * (cos_theta_i / eta - cos_theta_t))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normal_incidence() {
+        let eta: f32 = 1.5;
+        let r0 = ((eta - 1.) / (eta + 1.)).powi(2);
+        assert!((fresnel_dielectric(1., eta) - r0).abs() < 1e-6);
+        assert!((fresnel_dielectric(-1., eta) - r0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn total_internal_reflection() {
+        let eta = 1.5;
+        let cos_theta_i = -0.2;
+        assert_eq!(fresnel_dielectric(cos_theta_i, eta), 1.);
+        let wi = vec3((1. - cos_theta_i * cos_theta_i).sqrt(), 0., cos_theta_i);
+        assert!(!refract(wi, Vec3::Z, eta).0);
+    }
+
+    #[test]
+    fn refraction_follows_snell() {
+        let eta = 1.5;
+        for wi in [
+            vec3(0.3, 0.2, 0.9),
+            vec3(-0.8, 0.1, 0.3),
+            vec3(0.1, 0.5, -0.7),
+        ] {
+            let wi = wi.normalize();
+            let (valid, wo) = refract(wi, Vec3::Z, eta);
+            assert!(valid);
+            assert!((wo.length() - 1.).abs() < 1e-5);
+            assert!(wo.z * wi.z < 0.);
+
+            let sin_i = (1. - wi.z * wi.z).sqrt();
+            let sin_o = (1. - wo.z * wo.z).sqrt();
+            let (n_i, n_o) = if wi.z > 0. { (1., eta) } else { (eta, 1.) };
+            assert!((n_i * sin_i - n_o * sin_o).abs() < 1e-5);
+
+            // Both sides of the interface reflect the same amount
+            assert!((fresnel_dielectric(wi.z, eta) - fresnel_dielectric(wo.z, eta)).abs() < 1e-5);
+        }
+    }
+}
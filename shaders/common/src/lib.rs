@@ -1,10 +1,25 @@
 #![cfg_attr(target_arch = "spirv", no_std, feature(asm_experimental_arch,))]
 
+pub mod bsdf;
+pub mod emitter;
+mod frame;
+pub mod fresnel;
+pub mod microfacet;
+pub mod mis;
+pub mod path;
+pub mod rand;
+pub mod sampler;
+pub mod warp;
 mod workitems;
 pub mod workqueue;
+pub use frame::*;
 pub use workitems::*;
 
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+
 use bytemuck::*;
+use core::f32::consts::{FRAC_PI_2, PI};
 use spirv_std::glam::*;
 
 #[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
@@ -35,6 +50,8 @@ pub struct Emitter {
     pub irradiance: Texture,
     pub instance: u32,
     pub ty: u32,
+    pub cdf: u32,  // Offset of the triangle area cdf in the emitter cdf buffer
+    pub area: f32, // World space surface area
 }
 
 impl Emitter {
@@ -46,6 +63,8 @@ impl Emitter {
             irradiance,
             instance: 0,
             ty: Self::TY_ENV,
+            cdf: 0,
+            area: 0.,
         }
     }
     pub fn area(irradiance: Texture, instance: u32) -> Self {
@@ -53,6 +72,8 @@ impl Emitter {
             irradiance,
             instance,
             ty: Self::TY_AREA,
+            cdf: 0,
+            area: 0.,
         }
     }
 }
@@ -76,9 +97,34 @@ impl Default for Texture {
     }
 }
 
+///
+/// Source of the image textures referenced by `Texture::image`.
+///
+pub trait TextureSource {
+    fn sample(&self, texture: u32, uv: Vec2) -> Vec4;
+}
+
+#[cfg(target_arch = "spirv")]
+impl TextureSource
+    for spirv_std::RuntimeArray<
+        spirv_std::image::SampledImage<spirv_std::Image!(2D, type=f32, sampled)>,
+    >
+{
+    fn sample(&self, texture: u32, uv: Vec2) -> Vec4 {
+        unsafe { self.index(texture as usize).sample_by_lod(uv, 0.) }
+    }
+}
+
 impl Texture {
     const TY_CONSTANT: u32 = 0;
     const TY_IMAGE: u32 = 1;
+    pub fn eval(&self, uv: Vec2, textures: &impl TextureSource) -> Vec3 {
+        if self.ty == Self::TY_IMAGE {
+            textures.sample(self.texture, uv).xyz()
+        } else {
+            self.val
+        }
+    }
     pub fn constant(val: Vec3) -> Self {
         Self {
             ty: Self::TY_CONSTANT,
@@ -96,23 +142,101 @@ impl Texture {
 }
 
 #[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
-#[derive(Default, Clone, Copy)]
+#[derive(Clone, Copy)]
 #[repr(C)]
 pub struct Material {
     pub normal: Texture,
     pub base_color: Texture,
-    pub metallic_roughness: Texture,
+    pub metallic_roughness: Texture, // Roughness in the green and metallic in the blue channel
     pub transmission: Texture,
+    pub ior: f32,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            normal: Texture::constant(vec3(0., 0., 1.)),
+            base_color: Texture::default(),
+            metallic_roughness: Texture::default(),
+            transmission: Texture::default(),
+            ior: 1.5,
+        }
+    }
 }
 
+impl Material {
+    pub fn bsdf(&self, uv: Vec2, textures: &impl TextureSource) -> bsdf::PbrBsdf {
+        let base_color = self.base_color.eval(uv, textures);
+        let metallic_roughness = self.metallic_roughness.eval(uv, textures);
+        let transmission = self.transmission.eval(uv, textures);
+        bsdf::PbrBsdf::new(
+            base_color,
+            metallic_roughness.z,
+            metallic_roughness.y,
+            transmission.x,
+            self.ior,
+        )
+    }
+}
+
+///
+/// How the sensor of a camera is fit to a film of a different aspect ratio.
+///
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SensorFit {
+    /// Keeps the horizontal field of view of the sensor.
+    Horizontal = 0,
+    /// Keeps the vertical field of view of the sensor.
+    Vertical = 1,
+    /// Fits the sensor to the film so that it covers it, cropping the sensor.
+    Fill = 2,
+    /// Fits the sensor to the film so that it is fully visible, showing more than the sensor.
+    Overscan = 3,
+}
+
+impl SensorFit {
+    pub const ALL: [Self; 4] = [Self::Horizontal, Self::Vertical, Self::Fill, Self::Overscan];
+}
+
+/// Sensor fit of the push constants that keeps the fit of the camera.
+pub const SENSOR_FIT_CAMERA: u32 = u32::MAX;
+
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum CameraType {
+    /// Thin lens camera, a pinhole camera if the aperture radius is zero.
+    Perspective = 0,
+    /// Parallel rays covering `ortho_height` vertically.
+    Orthographic = 1,
+    /// Full sphere, longitude along x and latitude along y.
+    Equirectangular = 2,
+    /// Equidistant fisheye, `fov_y` across the image circle.
+    Fisheye = 3,
+}
+
+///
+/// Camera looking along its negative z axis with y up.
+/// The projection is independent of the resolution and computed for the film it renders to.
+///
 #[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
 #[derive(Clone, Copy)]
 #[repr(C, align(16))]
 pub struct Camera {
     pub to_world: [[f32; 4]; 4],
-    pub to_view: [[f32; 4]; 4],
-    pub near_clip: f32,
-    pub far_clip: f32,
+    pub ty: u32,                // `CameraType`
+    pub fov_y: f32,             // Vertical field of view of the sensor in radians
+    pub ortho_height: f32,      // Half the vertical extent of the view of orthographic cameras
+    pub aspect_ratio: f32,      // Of the sensor, 0 for a square sensor
+    pub fit: u32,               // `SensorFit`
+    pub near_clip: f32,         // Of the glTF camera, not applied to the rays
+    pub far_clip: f32,          // Of the glTF camera, not applied to the rays
+    pub aperture_radius: f32,   // 0 for a pinhole camera
+    pub focus_distance: f32,    // Distance of the plane in focus along the view axis
+    pub aperture_blades: u32,   // Corners of a polygonal aperture, circular if less than 3
+    pub aperture_rotation: f32, // Angle of the first corner of the aperture in radians
 }
 
 impl Camera {
@@ -120,29 +244,200 @@ impl Camera {
         to_world: Mat4,
         fov_y: f32,
         aspect_ratio: f32,
+        fit: SensorFit,
         near_clip: f32,
         far_clip: f32,
     ) -> Self {
-        let to_view = Mat4::perspective_lh(fov_y, aspect_ratio, near_clip, far_clip);
-        let to_view = Mat4::from_translation(vec3(1., 1., 0.)) * to_view;
-        let to_view = Mat4::from_scale(vec3(0.5, 0.5, 1.)) * to_view;
-        #[cfg(not(target_arch = "spirv"))]
-        {
-            //println!("{:#?}", to_view);
-        }
         Self {
             to_world: to_world.to_cols_array_2d(),
-            to_view: to_view.to_cols_array_2d(),
+            ty: CameraType::Perspective as u32,
+            fov_y,
+            ortho_height: 1.,
+            aspect_ratio,
+            fit: fit as u32,
             near_clip,
             far_clip,
-            //size: glam::uvec2(width, height),
+            aperture_radius: 0.,
+            focus_distance: 1.,
+            aperture_blades: 0,
+            aperture_rotation: 0.,
+        }
+    }
+    ///
+    /// Orthographic camera viewing `2 * xmag` by `2 * ymag` units.
+    ///
+    pub fn orthographic(
+        to_world: Mat4,
+        xmag: f32,
+        ymag: f32,
+        fit: SensorFit,
+        near_clip: f32,
+        far_clip: f32,
+    ) -> Self {
+        Self {
+            ty: CameraType::Orthographic as u32,
+            ortho_height: ymag,
+            ..Self::perspective(to_world, 0., xmag / ymag, fit, near_clip, far_clip)
+        }
+    }
+    pub fn equirectangular(to_world: Mat4, near_clip: f32, far_clip: f32) -> Self {
+        Self {
+            ty: CameraType::Equirectangular as u32,
+            ..Self::perspective(to_world, 0., 0., SensorFit::Fill, near_clip, far_clip)
+        }
+    }
+    ///
+    /// Fisheye camera with the field of view `fov` across its image circle.
+    /// Rendered with `SensorFit::Overscan` it shows the whole circle, with `SensorFit::Fill` the
+    /// circle covers the film.
+    ///
+    pub fn fisheye(
+        to_world: Mat4,
+        fov: f32,
+        fit: SensorFit,
+        near_clip: f32,
+        far_clip: f32,
+    ) -> Self {
+        Self {
+            ty: CameraType::Fisheye as u32,
+            ..Self::perspective(to_world, fov, 1., fit, near_clip, far_clip)
+        }
+    }
+    pub fn with_thin_lens(
+        self,
+        aperture_radius: f32,
+        focus_distance: f32,
+        aperture_blades: u32,
+        aperture_rotation: f32,
+    ) -> Self {
+        Self {
+            aperture_radius,
+            focus_distance,
+            aperture_blades,
+            aperture_rotation,
+            ..self
+        }
+    }
+    ///
+    /// Replaces the fit of the camera by `fit`, a `SensorFit`, unless it is `SENSOR_FIT_CAMERA`.
+    ///
+    pub fn with_fit(self, fit: u32) -> Self {
+        if fit == SENSOR_FIT_CAMERA {
+            self
+        } else {
+            Self { fit, ..self }
         }
     }
     pub fn to_world(&self) -> Mat4 {
         Mat4::from_cols_array_2d(&self.to_world)
     }
-    pub fn to_view(&self) -> Mat4 {
-        Mat4::from_cols_array_2d(&self.to_view)
+    ///
+    /// Tangents of half the horizontal and vertical field of view on a film with the aspect
+    /// ratio `film_aspect`.
+    ///
+    pub fn tan_half_fov(&self, film_aspect: f32) -> Vec2 {
+        self.fit_to_film((self.fov_y * 0.5).tan(), film_aspect)
+    }
+    ///
+    /// Scales the vertical extent `half_y` of the sensor to the horizontal and vertical extent
+    /// on a film with the aspect ratio `film_aspect`.
+    ///
+    fn fit_to_film(&self, half_y: f32, film_aspect: f32) -> Vec2 {
+        let aspect_ratio = if self.aspect_ratio > 0. {
+            self.aspect_ratio
+        } else {
+            1.
+        };
+
+        let wider = film_aspect > aspect_ratio;
+        let horizontal = if self.fit == SensorFit::Horizontal as u32 {
+            true
+        } else if self.fit == SensorFit::Vertical as u32 {
+            false
+        } else if self.fit == SensorFit::Fill as u32 {
+            wider
+        } else {
+            !wider
+        };
+
+        if horizontal {
+            let half_x = half_y * aspect_ratio;
+            vec2(half_x, half_x / film_aspect)
+        } else {
+            vec2(half_y * film_aspect, half_y)
+        }
+    }
+    ///
+    /// Generates the ray through `sample_pos`, given in normalized coordinates of a film with
+    /// the aspect ratio `film_aspect` and y pointing down.
+    /// `lens_sample` selects the point on the aperture the ray starts from.
+    /// Returns the ray and its weight, which is zero where the camera sees nothing, outside the
+    /// image circle of a fisheye camera. Such rays must not be traced.
+    ///
+    pub fn sample_ray(
+        &self,
+        sample_pos: Vec2,
+        lens_sample: Vec2,
+        film_aspect: f32,
+    ) -> (Ray3f, f32) {
+        // Film position with y up, in [-1, 1] across the film
+        let p = (sample_pos * 2. - Vec2::ONE) * vec2(1., -1.);
+
+        let mut weight = 1.;
+        let (o, d) = if self.ty == CameraType::Orthographic as u32 {
+            let p = p * self.fit_to_film(self.ortho_height, film_aspect);
+            (p.extend(0.), Vec3::NEG_Z)
+        } else if self.ty == CameraType::Equirectangular as u32 {
+            let phi = p.x * PI;
+            let theta = p.y * FRAC_PI_2;
+            let d = vec3(
+                phi.sin() * theta.cos(),
+                theta.sin(),
+                -phi.cos() * theta.cos(),
+            );
+            (Vec3::ZERO, d)
+        } else if self.ty == CameraType::Fisheye as u32 {
+            let p = p * self.fit_to_film(1., film_aspect);
+            let r = p.length();
+            if r > 1. {
+                weight = 0.;
+            }
+            let theta = r.min(1.) * self.fov_y * 0.5;
+            let dir = if r > 0. { p / r } else { Vec2::ZERO };
+            (Vec3::ZERO, (dir * theta.sin()).extend(-theta.cos()))
+        } else {
+            let d = (p * self.tan_half_fov(film_aspect)).extend(-1.);
+
+            // Start on the lens and pass through the point on the plane in focus the pinhole
+            // ray would hit
+            if self.aperture_radius > 0. {
+                let lens = if self.aperture_blades >= 3 {
+                    warp::square_to_uniform_polygon(
+                        lens_sample,
+                        self.aperture_blades,
+                        self.aperture_rotation,
+                    )
+                } else {
+                    warp::square_to_uniform_disk_concentric(lens_sample)
+                };
+                let o = (lens * self.aperture_radius).extend(0.);
+                (o, d * self.focus_distance - o)
+            } else {
+                (Vec3::ZERO, d)
+            }
+        };
+
+        let o = self.to_world().transform_point3(o);
+        let d = (self.to_world() * d.extend(0.)).xyz().normalize();
+
+        let ray = Ray3f {
+            o: o.extend(1.),
+            d: d.extend(1.),
+            tmin: 0.001,
+            tmax: 10000.,
+            t: 0.,
+        };
+        (ray, weight)
     }
 }
 
@@ -162,6 +457,9 @@ pub struct Ray3f {
 #[repr(C)]
 pub struct SurfaceInteraction {
     pub p: Vec4,
+    pub n: Vec4,  // Interpolated shading normal
+    pub wi: Vec4, // Direction towards the previous vertex
+    pub uv: Vec2,
     pub dist: f32,
     pub t: f32,
     pub instance: u32,
@@ -169,6 +467,66 @@ pub struct SurfaceInteraction {
     pub material: u32,
 }
 
+impl SurfaceInteraction {
+    ///
+    /// Reconstructs the surface interaction of `ray` with a triangle from the hit data written
+    /// by the closest hit shader.
+    ///
+    pub fn from_hit(
+        ray: &Ray3f,
+        instance_idx: u32,
+        primitive: u32,
+        barycentric: Vec2,
+        dist: f32,
+        instances: &[Instance],
+        meshes: &[Mesh],
+        indices: &[u32],
+        normals: &[Vec3],
+        uvs: &[Vec2],
+    ) -> Self {
+        let instance = instances[instance_idx as usize];
+        let mesh = meshes[instance.mesh as usize];
+
+        let idx = mesh.indices as usize + 3 * primitive as usize;
+        let i0 = indices[idx] as usize;
+        let i1 = indices[idx + 1] as usize;
+        let i2 = indices[idx + 2] as usize;
+
+        let w = vec3(
+            1. - barycentric.x - barycentric.y,
+            barycentric.x,
+            barycentric.y,
+        );
+
+        let n0 = normals[mesh.normals as usize + i0];
+        let n1 = normals[mesh.normals as usize + i1];
+        let n2 = normals[mesh.normals as usize + i2];
+        let n = n0 * w.x + n1 * w.y + n2 * w.z;
+        let n = (instance.to_world.inverse().transpose() * n.extend(0.))
+            .xyz()
+            .normalize();
+
+        let uv0 = uvs[mesh.uvs as usize + i0];
+        let uv1 = uvs[mesh.uvs as usize + i1];
+        let uv2 = uvs[mesh.uvs as usize + i2];
+        let uv = uv0 * w.x + uv1 * w.y + uv2 * w.z;
+
+        let wi = -ray.d.xyz();
+
+        Self {
+            p: (ray.o.xyz() + ray.d.xyz() * dist).extend(1.),
+            n: n.extend(0.),
+            wi: wi.extend(0.),
+            uv,
+            dist,
+            t: ray.t,
+            instance: instance_idx,
+            primitive,
+            material: instance.material,
+        }
+    }
+}
+
 #[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -181,14 +539,200 @@ pub struct LoopState {
 #[repr(C)]
 pub struct GenerateCameraRaysPc {
     pub camera: u32,
+    pub width: u32,  // Of the rendered region
+    pub height: u32, // Of the rendered region
+    pub film_width: u32,
+    pub film_height: u32,
+    pub offset_x: u32, // Of the rendered region on the film
+    pub offset_y: u32, // Of the rendered region on the film
+    pub sample: u32,   // Index of the sample
+    pub seed: u32,
+    pub sampler: u32,    // `sampler::SamplerType`
+    pub sensor_fit: u32, // `SensorFit` replacing the one of the camera, or `SENSOR_FIT_CAMERA`
 }
 
-#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct UpdateFilmPc {
+    pub sample: u32, // Number of samples accumulated before this one
+    pub width: u32,
+}
+
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct SampleBsdfPc {
+    pub mis: u32,       // `mis::MisHeuristic`
+    pub max_depth: u32, // No continuation ray is traced for hits at `max_depth - 1`
+    pub rr_depth: u32,  // Depth from which on paths are terminated by russian roulette
+    pub sampler: u32,   // `sampler::SamplerType`
+}
+
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct SortMaterialsPc {
+    pub material_count: u32,
+}
+
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct ScanPc {
+    pub count: u32,
+}
+
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct PartitionPc {
+    pub count: u32,         // Number of items if the source is not a `WorkQueue`
+    pub stride: u32,        // Size of an item in words
+    pub src_queue: u32,     // Whether the source has a `WorkQueue` header
+    pub dst_queue: u32,     // Whether the destination has a `WorkQueue` header
+    pub keep_rejected: u32, // Whether rejected items are kept after the accepted ones
+}
+
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 #[repr(C)]
-struct PushConstant {
+pub struct PathTracePc {
     pub camera: u32,
     pub max_depth: u32,
     pub rr_depth: u32,
     pub seed: u32,
+    pub spp: u32,
+    pub film_width: u32,
+    pub film_height: u32,
+    pub offset_x: u32,      // Of the rendered region on the film
+    pub offset_y: u32,      // Of the rendered region on the film
+    pub sample_offset: u32, // Samples accumulated in the color image by previous launches
+    pub mis: u32,           // `mis::MisHeuristic`
+    pub sampler: u32,       // `sampler::SamplerType`
+    pub sensor_fit: u32,    // `SensorFit` replacing the one of the camera, or `SENSOR_FIT_CAMERA`
+}
+
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+pub struct DisplayPc {
+    pub width: u32,
+    pub height: u32,
+    pub scale: f32, // `2^exposure`
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: Vec2, expected: Vec2) {
+        assert!(value.abs_diff_eq(expected, 1e-5), "{value} != {expected}");
+    }
+
+    #[test]
+    fn tan_half_fov_fits_sensor_to_film() {
+        // Sensor twice as wide as high, with a vertical field of view of 90°
+        let camera = |fit| Camera::perspective(Mat4::IDENTITY, FRAC_PI_2, 2., fit, 0.001, 100.);
+        let tan_half_fov = |film_aspect, fit| camera(fit).tan_half_fov(film_aspect);
+
+        // A film of the aspect ratio of the sensor shows exactly the sensor with any fit
+        for fit in SensorFit::ALL {
+            assert_close(tan_half_fov(2., fit), vec2(2., 1.));
+        }
+
+        // Wider film
+        assert_close(tan_half_fov(4., SensorFit::Horizontal), vec2(2., 0.5));
+        assert_close(tan_half_fov(4., SensorFit::Vertical), vec2(4., 1.));
+        assert_close(tan_half_fov(4., SensorFit::Fill), vec2(2., 0.5));
+        assert_close(tan_half_fov(4., SensorFit::Overscan), vec2(4., 1.));
+
+        // Taller film
+        assert_close(tan_half_fov(1., SensorFit::Horizontal), vec2(2., 2.));
+        assert_close(tan_half_fov(1., SensorFit::Vertical), vec2(1., 1.));
+        assert_close(tan_half_fov(1., SensorFit::Fill), vec2(1., 1.));
+        assert_close(tan_half_fov(1., SensorFit::Overscan), vec2(2., 2.));
+    }
+
+    #[test]
+    fn tan_half_fov_without_sensor_aspect_ratio() {
+        // A camera without an aspect ratio has a square sensor
+        for fit in SensorFit::ALL {
+            let camera = Camera::perspective(Mat4::IDENTITY, FRAC_PI_2, 0., fit, 0.001, 100.);
+            let square = Camera::perspective(Mat4::IDENTITY, FRAC_PI_2, 1., fit, 0.001, 100.);
+            for film_aspect in [0.5, 1., 3.] {
+                assert_close(
+                    camera.tan_half_fov(film_aspect),
+                    square.tan_half_fov(film_aspect),
+                );
+            }
+        }
+
+        let tan_half_fov = |film_aspect, fit| {
+            Camera::perspective(Mat4::IDENTITY, FRAC_PI_2, 0., fit, 0.001, 100.)
+                .tan_half_fov(film_aspect)
+        };
+        assert_close(tan_half_fov(2., SensorFit::Horizontal), vec2(1., 0.5));
+        assert_close(tan_half_fov(2., SensorFit::Vertical), vec2(2., 1.));
+        assert_close(tan_half_fov(2., SensorFit::Fill), vec2(1., 0.5));
+        assert_close(tan_half_fov(2., SensorFit::Overscan), vec2(2., 1.));
+        assert_close(tan_half_fov(0.5, SensorFit::Horizontal), vec2(1., 2.));
+        assert_close(tan_half_fov(0.5, SensorFit::Vertical), vec2(0.5, 1.));
+        assert_close(tan_half_fov(0.5, SensorFit::Fill), vec2(0.5, 1.));
+        assert_close(tan_half_fov(0.5, SensorFit::Overscan), vec2(1., 2.));
+    }
+
+    #[test]
+    fn with_fit_overrides_the_fit_of_the_camera() {
+        let camera = Camera::perspective(
+            Mat4::IDENTITY,
+            FRAC_PI_2,
+            2.,
+            SensorFit::Vertical,
+            0.001,
+            100.,
+        );
+        assert_eq!(
+            camera.with_fit(SENSOR_FIT_CAMERA).fit,
+            SensorFit::Vertical as u32
+        );
+        assert_eq!(
+            camera.with_fit(SensorFit::Overscan as u32).fit,
+            SensorFit::Overscan as u32
+        );
+        assert_close(
+            camera
+                .with_fit(SensorFit::Horizontal as u32)
+                .tan_half_fov(4.),
+            vec2(2., 0.5),
+        );
+    }
+
+    #[test]
+    fn fisheye_sees_nothing_outside_the_image_circle() {
+        let camera = Camera::fisheye(Mat4::IDENTITY, PI, SensorFit::Overscan, 0.001, 100.);
+        let sample_ray = |x, y| camera.sample_ray(vec2(x, y), Vec2::ZERO, 1.);
+
+        let (ray, weight) = sample_ray(0.5, 0.5);
+        assert_eq!(weight, 1.);
+        assert!(ray.d.xyz().abs_diff_eq(Vec3::NEG_Z, 1e-5));
+
+        // The edge of the circle looks sideways with a field of view of 180°
+        let (ray, weight) = sample_ray(0.9999, 0.5);
+        assert_eq!(weight, 1.);
+        assert!(ray.d.xyz().abs_diff_eq(Vec3::X, 1e-3));
+
+        for (x, y) in [(0.01, 0.01), (0.99, 0.01), (0.01, 0.99), (0.99, 0.99)] {
+            assert_eq!(sample_ray(x, y).1, 0.);
+        }
+
+        // Other cameras see something everywhere
+        for camera in [
+            Camera::perspective(
+                Mat4::IDENTITY,
+                FRAC_PI_2,
+                0.,
+                SensorFit::Overscan,
+                0.001,
+                100.,
+            ),
+            Camera::equirectangular(Mat4::IDENTITY, 0.001, 100.),
+        ] {
+            let (_, weight) = camera.sample_ray(vec2(0.01, 0.01), Vec2::ZERO, 1.);
+            assert_eq!(weight, 1.);
+        }
+    }
 }
@@ -0,0 +1,135 @@
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+
+use core::f32::consts::PI;
+use spirv_std::glam::*;
+
+///
+/// Isotropic GGX (Trowbridge-Reitz) microfacet distribution.
+/// Directions are given in the local shading frame.
+///
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy)]
+pub struct GGX {
+    pub alpha: f32,
+}
+
+impl GGX {
+    pub fn new(alpha: f32) -> Self {
+        Self {
+            alpha: alpha.max(1e-3),
+        }
+    }
+    pub fn d(&self, m: Vec3) -> f32 {
+        if m.z <= 0. {
+            return 0.;
+        }
+        let a2 = self.alpha * self.alpha;
+        let denom = m.z * m.z * (a2 - 1.) + 1.;
+        a2 / (PI * denom * denom)
+    }
+    pub fn lambda(&self, w: Vec3) -> f32 {
+        let cos2 = w.z * w.z;
+        if cos2 == 0. {
+            return 0.;
+        }
+        let tan2 = (1. - cos2).max(0.) / cos2;
+        ((1. + self.alpha * self.alpha * tan2).sqrt() - 1.) * 0.5
+    }
+    pub fn g1(&self, w: Vec3) -> f32 {
+        1. / (1. + self.lambda(w))
+    }
+    pub fn g(&self, wi: Vec3, wo: Vec3) -> f32 {
+        1. / (1. + self.lambda(wi) + self.lambda(wo))
+    }
+    ///
+    /// Density of the normals `m` visible from direction `w`.
+    ///
+    pub fn d_visible(&self, w: Vec3, m: Vec3) -> f32 {
+        if w.z == 0. {
+            return 0.;
+        }
+        self.g1(w) / w.z.abs() * self.d(m) * w.dot(m).abs()
+    }
+    ///
+    /// Samples a visible normal as seen from `w` ("Sampling the GGX Distribution of Visible
+    /// Normals", Heitz 2018).
+    ///
+    pub fn sample_visible(&self, w: Vec3, sample: Vec2) -> Vec3 {
+        let w = if w.z < 0. { -w } else { w };
+        let wh = vec3(self.alpha * w.x, self.alpha * w.y, w.z).normalize();
+
+        let t1 = if wh.z < 0.99999 {
+            Vec3::Z.cross(wh).normalize()
+        } else {
+            Vec3::X
+        };
+        let t2 = wh.cross(t1);
+
+        let r = sample.x.sqrt();
+        let phi = 2. * PI * sample.y;
+        let p = vec2(r * phi.cos(), r * phi.sin());
+        let h = (1. - p.x * p.x).sqrt();
+        let p = vec2(p.x, h + (p.y - h) * (1. + wh.z) * 0.5);
+        let pz = (1. - p.length_squared()).max(0.).sqrt();
+
+        let nh = t1 * p.x + t2 * p.y + wh * pz;
+        vec3(self.alpha * nh.x, self.alpha * nh.y, nh.z.max(1e-6)).normalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// Integrates `f` over the upper hemisphere with the midpoint rule in `cos(theta)` and `phi`.
+    ///
+    fn integrate_hemisphere(f: impl Fn(Vec3) -> f32) -> f32 {
+        let (nz, nphi) = (1024, 256);
+        let dw = (1. / nz as f32) * (2. * PI / nphi as f32);
+        let mut sum = 0.;
+        for i in 0..nz {
+            for j in 0..nphi {
+                let z = (i as f32 + 0.5) / nz as f32;
+                let phi = (j as f32 + 0.5) * 2. * PI / nphi as f32;
+                let r = (1. - z * z).sqrt();
+                sum += f(vec3(r * phi.cos(), r * phi.sin(), z)) * dw;
+            }
+        }
+        sum
+    }
+
+    #[test]
+    fn projected_normals_cover_unit_area() {
+        for alpha in [0.2, 0.5, 1.] {
+            let distr = GGX::new(alpha);
+            let area = integrate_hemisphere(|m| distr.d(m) * m.z);
+            assert!((area - 1.).abs() < 0.01, "alpha {}: {}", alpha, area);
+        }
+    }
+
+    #[test]
+    fn visible_normals_are_normalized() {
+        for alpha in [0.2, 0.5, 1.] {
+            let distr = GGX::new(alpha);
+            for w in [vec3(0., 0., 1.), vec3(0.6, 0., 0.8), vec3(0.3, -0.9, 0.3)] {
+                let w = w.normalize();
+                let visible = integrate_hemisphere(|m| {
+                    if w.dot(m) > 0. {
+                        distr.d_visible(w, m)
+                    } else {
+                        0.
+                    }
+                });
+                assert!(
+                    (visible - 1.).abs() < 0.01,
+                    "alpha {} from {:?}: {}",
+                    alpha,
+                    w,
+                    visible
+                );
+            }
+        }
+    }
+}
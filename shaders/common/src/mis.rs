@@ -0,0 +1,37 @@
+///
+/// Heuristic used to combine BSDF and emitter sampling.
+/// `None` disables MIS, in which case emitters are only accounted for by emitter sampling.
+///
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MisHeuristic {
+    None = 0,
+    Balance = 1,
+    Power = 2,
+}
+
+///
+/// Weight of a sample drawn with density `pdf_a`, that could also have been drawn with density
+/// `pdf_b`.
+///
+pub fn mis_weight(heuristic: u32, pdf_a: f32, pdf_b: f32) -> f32 {
+    if heuristic == MisHeuristic::Balance as u32 {
+        let sum = pdf_a + pdf_b;
+        if sum > 0. {
+            pdf_a / sum
+        } else {
+            0.
+        }
+    } else if heuristic == MisHeuristic::Power as u32 {
+        let a2 = pdf_a * pdf_a;
+        let sum = a2 + pdf_b * pdf_b;
+        if sum > 0. {
+            a2 / sum
+        } else {
+            0.
+        }
+    } else {
+        1.
+    }
+}
@@ -0,0 +1,178 @@
+use spirv_std::glam::*;
+
+use crate::emitter::select_emitter;
+use crate::mis::{mis_weight, MisHeuristic};
+use crate::sampler::{PathSampler, Sampler};
+use crate::{Emitter, Frame, Instance, Material, Mesh, Ray3f, SurfaceInteraction, TextureSource};
+
+///
+/// Outcome of shading one vertex of a path with `shade_vertex`.
+///
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy)]
+pub struct VertexShading {
+    /// Emission of the hit surface, weighted against next-event estimation of the previous
+    /// vertex.
+    pub emission: Vec3,
+    /// Contribution of next-event estimation, which only counts if `shadow_ray` is unoccluded.
+    pub direct: Vec3,
+    pub shadow_ray: Ray3f,
+    /// Whether the path continues with `ray`, it ends if it is absorbed, killed by Russian
+    /// roulette or reaches the maximum depth.
+    pub continues: bool,
+    pub ray: Ray3f,
+    pub throughput: Vec3,
+    pub bsdf_pdf: f32,
+}
+
+///
+/// Shades the hit `si` of a path at `depth`, shared by all integrators.
+///
+/// `throughput` and `bsdf_pdf` are the ones the path arrived with, `mis` is a `MisHeuristic`.
+/// Adds the emission of the hit surface, samples an emitter for next-event estimation and
+/// samples the BSDF for the continuation ray, followed by Russian roulette from `rr_depth` on.
+/// On the last bounce next-event estimation gets the full weight, as there is no continuation
+/// ray that could find emitters.
+///
+pub fn shade_vertex(
+    si: &SurfaceInteraction,
+    throughput: Vec3,
+    depth: u32,
+    bsdf_pdf: f32,
+    sampler: &mut PathSampler,
+    mis: u32,
+    max_depth: u32,
+    rr_depth: u32,
+    instances: &[Instance],
+    meshes: &[Mesh],
+    indices: &[u32],
+    positions: &[Vec3],
+    uvs: &[Vec2],
+    emitters: &[Emitter],
+    emitter_cdfs: &[f32],
+    materials: &[Material],
+    textures: &impl TextureSource,
+) -> VertexShading {
+    let wi = si.wi.xyz();
+    let n = si.n.xyz();
+
+    let mut shading = VertexShading {
+        emission: Vec3::ZERO,
+        direct: Vec3::ZERO,
+        shadow_ray: Ray3f {
+            o: si.p,
+            d: Vec4::ZERO,
+            tmin: 0.,
+            tmax: 0.,
+            t: si.t,
+        },
+        continues: false,
+        ray: Ray3f {
+            o: si.p,
+            d: Vec4::ZERO,
+            tmin: 0.,
+            tmax: 0.,
+            t: si.t,
+        },
+        throughput: Vec3::ZERO,
+        bsdf_pdf: 0.,
+    };
+
+    // Emission of the hit surface, weighted against next-event estimation of the previous
+    // vertex
+    let instance = instances[si.instance as usize];
+    if instance.emitter >= 0 {
+        let emitter = emitters[instance.emitter as usize];
+        let radiance = emitter.eval(si, instances, meshes, indices, positions, textures);
+        let weight = if depth == 0 {
+            1.
+        } else if mis == MisHeuristic::None as u32 {
+            0.
+        } else {
+            let emitter_pdf = emitter.pdf_direction(si, instances, meshes, indices, positions)
+                / emitters.len() as f32;
+            mis_weight(mis, bsdf_pdf, emitter_pdf)
+        };
+        shading.emission = throughput * radiance * weight;
+    }
+
+    let material = materials[si.material as usize];
+    let bsdf = material.bsdf(si.uv, textures);
+
+    // Surfaces without transmission are treated as two-sided
+    let n = if bsdf.transmission == 0. && n.dot(wi) < 0. {
+        -n
+    } else {
+        n
+    };
+    let frame = Frame::from_normal(n);
+
+    sampler.state.start_bounce(depth);
+
+    // Without a continuation ray emitters are only found by next-event estimation
+    let last_bounce = depth + 1 >= max_depth;
+
+    // Next-event estimation
+    if !emitters.is_empty() {
+        let (emitter_idx, emitter_pdf, sample1) = select_emitter(emitters, sampler.next_1d());
+        let (ds, emitter_weight) = emitters[emitter_idx as usize].sample_direction(
+            si.p.xyz(),
+            sample1,
+            sampler.next_2d(),
+            instances,
+            meshes,
+            indices,
+            positions,
+            uvs,
+            emitter_cdfs,
+            textures,
+        );
+        if ds.pdf > 0. {
+            let bsdf_val = bsdf.eval(frame.to_local(wi), frame.to_local(ds.d));
+            let bsdf_pdf = bsdf.pdf(frame.to_local(wi), frame.to_local(ds.d));
+            let weight = if last_bounce {
+                1.
+            } else {
+                mis_weight(mis, ds.pdf * emitter_pdf, bsdf_pdf)
+            };
+            shading.direct = throughput * bsdf_val * emitter_weight * (weight / emitter_pdf);
+            shading.shadow_ray = Ray3f {
+                o: si.p,
+                d: ds.d.extend(0.),
+                tmin: 0.001,
+                tmax: ds.dist - 0.001,
+                t: si.t,
+            };
+        }
+    }
+
+    if last_bounce {
+        return shading;
+    }
+    let (bs, weight) = bsdf.sample(frame.to_local(wi), sampler.next_1d(), sampler.next_2d());
+    if bs.pdf <= 0. {
+        return shading;
+    }
+    let mut throughput = throughput * weight;
+
+    // Russian roulette
+    if depth >= rr_depth {
+        let q = throughput.max_element().min(0.95);
+        if sampler.next_1d() >= q {
+            return shading;
+        }
+        throughput /= q;
+    }
+
+    shading.continues = true;
+    shading.ray = Ray3f {
+        o: si.p,
+        d: frame.to_world(bs.wo).extend(0.),
+        tmin: 0.001,
+        tmax: 10000.,
+        t: si.t,
+    };
+    shading.throughput = throughput;
+    shading.bsdf_pdf = bs.pdf;
+    shading
+}
@@ -0,0 +1,32 @@
+///
+/// PCG hash function ("Hash Functions for GPU Rendering", Jarzynski and Olano 2020).
+///
+pub fn pcg(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+///
+/// Small random number generator, stepping its state with the pcg hash.
+///
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct Rng {
+    pub state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        Self { state: pcg(seed) }
+    }
+    pub fn next_u32(&mut self) -> u32 {
+        self.state = pcg(self.state);
+        self.state
+    }
+    /// Returns a uniformly distributed float in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 * (1. / (1u32 << 24) as f32)
+    }
+}
@@ -0,0 +1,399 @@
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+
+use spirv_std::glam::*;
+
+use crate::rand::pcg;
+
+///
+/// Source of sample values in [0, 1).
+/// Every call consumes one dimension of the sample.
+///
+pub trait Sampler {
+    fn next_1d(&mut self) -> f32;
+    fn next_2d(&mut self) -> Vec2 {
+        let x = self.next_1d();
+        let y = self.next_1d();
+        vec2(x, y)
+    }
+}
+
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SamplerType {
+    Independent = 0,
+    Halton = 1,
+    Sobol = 2,
+    BlueNoise = 3,
+}
+
+///
+/// State shared by all samplers, stored in the work items so that a path keeps consuming the
+/// dimensions of its sample across bounces.
+///
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct SamplerState {
+    pub pixel: u32, // x in the lower, y in the upper 16 bits
+    pub sample: u32,
+    pub dimension: u32,
+    pub seed: u32,
+}
+
+/// Dimensions used by the camera ray, for the position on the film and on the lens.
+pub const CAMERA_DIMENSIONS: u32 = 4;
+/// Dimensions reserved for every bounce, unused ones are skipped.
+pub const BOUNCE_DIMENSIONS: u32 = 8;
+
+impl SamplerState {
+    pub fn new(pixel: UVec2, sample: u32, seed: u32) -> Self {
+        Self {
+            pixel: (pixel.x & 0xffff) | (pixel.y << 16),
+            sample,
+            dimension: 0,
+            seed,
+        }
+    }
+    pub fn pixel(&self) -> UVec2 {
+        uvec2(self.pixel & 0xffff, self.pixel >> 16)
+    }
+    ///
+    /// Moves to the first dimension of the given bounce, so the same dimensions are used for the
+    /// same decisions regardless of how many were consumed before.
+    ///
+    pub fn start_bounce(&mut self, depth: u32) {
+        self.dimension = CAMERA_DIMENSIONS + depth * BOUNCE_DIMENSIONS;
+    }
+    fn pixel_hash(&self) -> u32 {
+        pcg(self.pixel ^ pcg(self.seed))
+    }
+}
+
+///
+/// Independent uniform random numbers from the pcg hash.
+///
+pub struct IndependentSampler {
+    pub state: SamplerState,
+}
+
+impl Sampler for IndependentSampler {
+    fn next_1d(&mut self) -> f32 {
+        let s = &mut self.state;
+        let hash = pcg(s.pixel_hash() ^ pcg(s.sample ^ pcg(s.dimension)));
+        s.dimension += 1;
+        to_f32(hash)
+    }
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+///
+/// Halton sequence with random digit scrambling per pixel and dimension.
+/// Dimensions beyond the number of bases reuse them with a different scramble.
+///
+pub struct HaltonSampler {
+    pub state: SamplerState,
+}
+
+impl Sampler for HaltonSampler {
+    fn next_1d(&mut self) -> f32 {
+        let s = &mut self.state;
+        let base = PRIMES[(s.dimension % PRIMES.len() as u32) as usize];
+        let seed = pcg(s.pixel_hash() ^ pcg(s.dimension));
+        s.dimension += 1;
+        scrambled_radical_inverse(s.sample, base, seed)
+    }
+}
+
+///
+/// Radical inverse of `index` in `base`, shifting every digit by a hashed offset.
+///
+fn scrambled_radical_inverse(index: u32, base: u32, seed: u32) -> f32 {
+    let inv_base = 1. / base as f32;
+    let mut index = index;
+    let mut inv_base_n = 1.;
+    let mut value = 0.;
+    let mut level = 0;
+    // Scrambled trailing zero digits contribute as well, up to the float precision
+    while inv_base_n > 1e-7 {
+        let digit = index % base;
+        let scrambled = (digit + pcg(seed ^ level) % base) % base;
+        inv_base_n *= inv_base;
+        value += scrambled as f32 * inv_base_n;
+        index /= base;
+        level += 1;
+    }
+    value.min(1. - f32::EPSILON)
+}
+
+///
+/// Owen-scrambled Sobol sequence ("Practical Hash-based Owen Scrambling", Burley 2020).
+/// Uses the first four Sobol dimensions, higher dimensions are padded with independently
+/// shuffled and scrambled copies.
+///
+pub struct SobolSampler {
+    pub state: SamplerState,
+}
+
+impl Sampler for SobolSampler {
+    fn next_1d(&mut self) -> f32 {
+        let s = &mut self.state;
+        let dim = s.dimension % 4;
+        let seed = pcg(s.pixel_hash() ^ pcg(s.dimension / 4));
+        s.dimension += 1;
+
+        let index = nested_uniform_scramble(s.sample, seed);
+        to_f32(nested_uniform_scramble(
+            sobol(index, dim),
+            hash_combine(seed, dim),
+        ))
+    }
+}
+
+const fn sobol_directions() -> [[u32; 32]; 4] {
+    // Initial direction numbers and primitive polynomials of dimensions 2 to 4 from Joe and
+    // Kuo's new-joe-kuo-6.21201
+    let s = [1, 2, 3];
+    let a = [0, 1, 1];
+    let m = [[1, 0, 0], [1, 3, 0], [1, 3, 1]];
+
+    let mut v = [[0u32; 32]; 4];
+    let mut k = 0;
+    while k < 32 {
+        v[0][k] = 1 << (31 - k);
+        k += 1;
+    }
+    let mut d = 0;
+    while d < 3 {
+        let mut k = 0;
+        while k < 32 {
+            v[d + 1][k] = if k < s[d] {
+                m[d][k] << (31 - k)
+            } else {
+                let mut x = v[d + 1][k - s[d]] ^ (v[d + 1][k - s[d]] >> s[d]);
+                let mut j = 1;
+                while j < s[d] {
+                    if (a[d] >> (s[d] - 1 - j)) & 1 != 0 {
+                        x ^= v[d + 1][k - j];
+                    }
+                    j += 1;
+                }
+                x
+            };
+            k += 1;
+        }
+        d += 1;
+    }
+    v
+}
+
+const SOBOL_DIRECTIONS: [[u32; 32]; 4] = sobol_directions();
+
+fn sobol(index: u32, dim: u32) -> u32 {
+    let mut x = 0;
+    let mut index = index;
+    let mut bit = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            x ^= SOBOL_DIRECTIONS[dim as usize][bit];
+        }
+        index >>= 1;
+        bit += 1;
+    }
+    x
+}
+
+fn laine_karras_permutation(x: u32, seed: u32) -> u32 {
+    let mut x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn hash_combine(seed: u32, v: u32) -> u32 {
+    seed ^ (v
+        .wrapping_add(seed << 6)
+        .wrapping_add(seed >> 2)
+        .wrapping_add(0x9e3779b9))
+}
+
+///
+/// Additive recurrence over the samples, offset per pixel by interleaved gradient noise
+/// ("Next Generation Post Processing in Call of Duty: Advanced Warfare", Jimenez 2014).
+/// The error of neighbouring pixels is anti-correlated, which distributes it as blue noise
+/// over the image.
+///
+pub struct BlueNoiseSampler {
+    pub state: SamplerState,
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn next_1d(&mut self) -> f32 {
+        let s = &mut self.state;
+        // Shift the dither pattern per dimension so dimensions are decorrelated
+        let shift = pcg(s.dimension ^ pcg(s.seed));
+        let pixel = s.pixel() + uvec2(shift & 0xff, (shift >> 8) & 0xff);
+        let offset = interleaved_gradient_noise(pixel.as_vec2());
+        // Weyl sequence with an irrational step per dimension
+        let alpha = fract(PRIMES[(s.dimension % PRIMES.len() as u32) as usize] as f32 * 0.618034);
+        s.dimension += 1;
+        fract(offset + s.sample as f32 * alpha).min(1. - f32::EPSILON)
+    }
+}
+
+fn interleaved_gradient_noise(p: Vec2) -> f32 {
+    fract(52.982918 * fract(p.dot(vec2(0.06711056, 0.00583715))))
+}
+
+fn fract(x: f32) -> f32 {
+    x - x.floor()
+}
+
+fn to_f32(x: u32) -> f32 {
+    (x >> 8) as f32 * (1. / (1u32 << 24) as f32)
+}
+
+///
+/// Sampler whose type is chosen at runtime, e.g. by a push constant.
+///
+pub struct PathSampler {
+    pub ty: u32, // `SamplerType`
+    pub state: SamplerState,
+}
+
+impl PathSampler {
+    pub fn new(ty: u32, state: SamplerState) -> Self {
+        Self { ty, state }
+    }
+}
+
+impl Sampler for PathSampler {
+    fn next_1d(&mut self) -> f32 {
+        let state = self.state;
+        let (value, state) = if self.ty == SamplerType::Halton as u32 {
+            let mut sampler = HaltonSampler { state };
+            (sampler.next_1d(), sampler.state)
+        } else if self.ty == SamplerType::Sobol as u32 {
+            let mut sampler = SobolSampler { state };
+            (sampler.next_1d(), sampler.state)
+        } else if self.ty == SamplerType::BlueNoise as u32 {
+            let mut sampler = BlueNoiseSampler { state };
+            (sampler.next_1d(), sampler.state)
+        } else {
+            let mut sampler = IndependentSampler { state };
+            (sampler.next_1d(), sampler.state)
+        };
+        self.state = state;
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPES: [SamplerType; 4] = [
+        SamplerType::Independent,
+        SamplerType::Halton,
+        SamplerType::Sobol,
+        SamplerType::BlueNoise,
+    ];
+
+    fn sequence(ty: SamplerType, pixel: UVec2, sample: u32, seed: u32) -> [f32; 32] {
+        let mut sampler = PathSampler::new(ty as u32, SamplerState::new(pixel, sample, seed));
+        let mut values = [0.; 32];
+        for value in &mut values {
+            *value = sampler.next_1d();
+        }
+        values
+    }
+
+    ///
+    /// Checks that each of `n` equally sized strata of [0, 1) holds exactly one of the first `n`
+    /// samples of `dimension`.
+    ///
+    fn assert_stratified(ty: SamplerType, pixel: UVec2, dimension: u32, n: u32) {
+        let mut strata = [false; 256];
+        for sample in 0..n {
+            let mut state = SamplerState::new(pixel, sample, 7);
+            state.dimension = dimension;
+            let value = PathSampler::new(ty as u32, state).next_1d();
+            let stratum = (value * n as f32) as usize;
+            assert!(
+                !strata[stratum],
+                "{ty:?} dimension {dimension}: stratum {stratum} of {n} hit twice"
+            );
+            strata[stratum] = true;
+        }
+    }
+
+    #[test]
+    fn same_state_gives_same_sequence() {
+        for ty in TYPES {
+            for (pixel, sample) in [(uvec2(0, 0), 0), (uvec2(17, 5), 3), (uvec2(640, 480), 99)] {
+                let a = sequence(ty, pixel, sample, 1);
+                let b = sequence(ty, pixel, sample, 1);
+                assert_eq!(a, b, "{ty:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn seeds_give_different_sequences() {
+        for ty in TYPES {
+            for (pixel, sample) in [(uvec2(0, 0), 0), (uvec2(17, 5), 3), (uvec2(640, 480), 99)] {
+                let a = sequence(ty, pixel, sample, 1);
+                let b = sequence(ty, pixel, sample, 2);
+                assert_ne!(a, b, "{ty:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn values_are_in_unit_interval() {
+        for ty in TYPES {
+            for y in 0..8 {
+                for x in 0..8 {
+                    for sample in 0..64 {
+                        for value in sequence(ty, uvec2(x * 37, y * 91), sample, x ^ y) {
+                            assert!((0. ..1.).contains(&value), "{ty:?}: {value}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn halton_is_stratified() {
+        for pixel in [uvec2(0, 0), uvec2(3, 9), uvec2(1000, 20)] {
+            // Bases 2, 3 and 5
+            assert_stratified(SamplerType::Halton, pixel, 0, 128);
+            assert_stratified(SamplerType::Halton, pixel, 1, 81);
+            assert_stratified(SamplerType::Halton, pixel, 2, 125);
+        }
+    }
+
+    #[test]
+    fn sobol_is_stratified() {
+        for pixel in [uvec2(0, 0), uvec2(3, 9), uvec2(1000, 20)] {
+            // Includes the padded dimensions beyond the first four
+            for dimension in 0..12 {
+                for n in [1, 2, 16, 256] {
+                    assert_stratified(SamplerType::Sobol, pixel, dimension, n);
+                }
+            }
+        }
+    }
+}
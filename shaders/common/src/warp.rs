@@ -0,0 +1,54 @@
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+
+use core::f32::consts::{FRAC_1_PI, FRAC_PI_4, TAU};
+use spirv_std::glam::*;
+
+///
+/// Maps the unit square to the unit disk, preserving relative areas (Shirley and Chiu 1997).
+///
+pub fn square_to_uniform_disk_concentric(sample: Vec2) -> Vec2 {
+    let x = 2. * sample.x - 1.;
+    let y = 2. * sample.y - 1.;
+
+    if x == 0. && y == 0. {
+        return Vec2::ZERO;
+    }
+
+    let (r, phi) = if x.abs() > y.abs() {
+        (x, FRAC_PI_4 * (y / x))
+    } else {
+        (y, 2. * FRAC_PI_4 - FRAC_PI_4 * (x / y))
+    };
+    vec2(r * phi.cos(), r * phi.sin())
+}
+
+///
+/// Maps the unit square uniformly to the regular polygon with `n` corners on the unit circle,
+/// the first one at the angle `rotation`.
+///
+pub fn square_to_uniform_polygon(sample: Vec2, n: u32, rotation: f32) -> Vec2 {
+    // Select one of the triangles between the center and two adjacent corners
+    let x = sample.x * n as f32;
+    let i = x.floor().min(n as f32 - 1.);
+    let u = x - i;
+
+    let phi0 = rotation + TAU * i / n as f32;
+    let phi1 = phi0 + TAU / n as f32;
+    let a = vec2(phi0.cos(), phi0.sin());
+    let b = vec2(phi1.cos(), phi1.sin());
+
+    // Uniform point in the triangle, one corner of which is the center
+    let su = u.sqrt();
+    su * ((1. - sample.y) * a + sample.y * b)
+}
+
+pub fn square_to_cosine_hemisphere(sample: Vec2) -> Vec3 {
+    let p = square_to_uniform_disk_concentric(sample);
+    let z = (1. - p.length_squared()).max(0.).sqrt();
+    p.extend(z)
+}
+
+pub fn square_to_cosine_hemisphere_pdf(v: Vec3) -> f32 {
+    FRAC_1_PI * v.z.max(0.)
+}
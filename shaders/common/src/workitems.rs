@@ -3,6 +3,7 @@ use core::ops::Index;
 use spirv_std::arch::{atomic_i_add, atomic_i_increment};
 use spirv_std::{glam::*, RuntimeArray};
 
+use crate::sampler::SamplerState;
 use crate::{Ray3f, SurfaceInteraction};
 
 #[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
@@ -28,6 +29,9 @@ pub struct RayWorkItem {
     pub ray: Ray3f,
     pub throughput: Vec4,
     pub pixel_idx: u32,
+    pub depth: u32,
+    pub bsdf_pdf: f32, // Solid angle pdf of the BSDF sample that generated the ray
+    pub sampler: SamplerState,
 }
 
 #[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
@@ -35,5 +39,18 @@ pub struct RayWorkItem {
 #[repr(C)]
 pub struct MaterialEvalWorkItem {
     pub si: SurfaceInteraction,
+    pub throughput: Vec4,
+    pub pixel_idx: u32,
+    pub depth: u32,
+    pub bsdf_pdf: f32,
+    pub sampler: SamplerState,
+}
+
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct ShadowRayWorkItem {
+    pub ray: Ray3f,
+    pub radiance: Vec4, // Contribution to the pixel if the ray is unoccluded
     pub pixel_idx: u32,
 }
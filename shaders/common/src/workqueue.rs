@@ -3,6 +3,22 @@ use core::ops::{Index, IndexMut};
 use spirv_std::arch::atomic_i_increment;
 use spirv_std::RuntimeArray;
 
+///
+/// Number of threads per workgroup of the queue kernels. `#[spirv(compute(threads(..)))]` only
+/// accepts a literal, so the entry points repeat this value.
+///
+pub const WORKGROUP_SIZE: u32 = 64;
+
+///
+/// Size of the `WorkQueue` header in 32-bit words, the items start right after it.
+///
+pub const HEADER_WORDS: u32 = 4;
+
+///
+/// Index of the `len` word within the `WorkQueue` header.
+///
+pub const LEN_WORD: u32 = 1;
+
 #[repr(C, align(16))]
 pub struct WorkQueue<T: Copy> {
     pub cap: u32,
@@ -58,3 +74,39 @@ macro_rules! index {
 index!(usize);
 index!(u32);
 index!(u64);
+
+///
+/// Layout of `VkDispatchIndirectCommand`.
+///
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct DispatchIndirectCommand {
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+}
+
+///
+/// Layout of `VkTraceRaysIndirectCommandKHR`.
+///
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct TraceRaysIndirectCommand {
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+}
+
+///
+/// Indirect arguments to process every item of a `WorkQueue`, either with a compute kernel of
+/// `WORKGROUP_SIZE` threads per workgroup or with a ray generation shader.
+///
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct IndirectArgs {
+    pub dispatch: DispatchIndirectCommand,
+    pub trace_rays: TraceRaysIndirectCommand,
+}
@@ -0,0 +1,79 @@
+#![no_std]
+
+use common::workqueue::*;
+use common::*;
+use spirv_std::glam::*;
+use spirv_std::ray_tracing::{AccelerationStructure, CommittedIntersection, RayFlags};
+use spirv_std::*;
+
+///
+/// Equivalent of `intersect_closest` as a compute shader using ray queries, so no shader
+/// binding table is needed.
+///
+#[spirv(compute(threads(64)))]
+pub fn intersect_closest_ray_query(
+    #[spirv(global_invocation_id)] pos: UVec3,
+    #[spirv(uniform_constant, descriptor_set = 0, binding = 0)] accel: &AccelerationStructure,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] rays: &WorkQueue<RayWorkItem>,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] material_eval_queue: &mut WorkQueue<
+        MaterialEvalWorkItem,
+    >,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] instances: &[Instance],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] meshes: &[Mesh],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] indices: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] normals: &[Vec3],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] uvs: &[Vec2],
+) {
+    if pos.x >= rays.len {
+        return;
+    }
+    let RayWorkItem {
+        ray,
+        throughput,
+        pixel_idx,
+        depth,
+        bsdf_pdf,
+        sampler,
+    } = *rays.item(pos.x);
+
+    spirv_std::ray_query!(let mut query);
+    unsafe {
+        query.initialize(
+            accel,
+            RayFlags::OPAQUE,
+            0xff,
+            ray.o.xyz(),
+            ray.tmin,
+            ray.d.xyz(),
+            ray.tmax,
+        );
+        while query.proceed() {}
+
+        if query.get_committed_intersection_type() == CommittedIntersection::Triangle {
+            let instance = query.get_committed_intersection_instance_id();
+            let primitive = query.get_committed_intersection_primitive_index();
+            let barycentric: Vec2 = query.get_committed_intersection_barycentrics();
+            let dist = query.get_committed_intersection_t();
+
+            material_eval_queue.push(MaterialEvalWorkItem {
+                si: SurfaceInteraction::from_hit(
+                    &ray,
+                    instance,
+                    primitive,
+                    barycentric,
+                    dist,
+                    instances,
+                    meshes,
+                    indices,
+                    normals,
+                    uvs,
+                ),
+                throughput,
+                pixel_idx,
+                depth,
+                bsdf_pdf,
+                sampler,
+            });
+        }
+    }
+}
@@ -1,9 +1,11 @@
 #![no_std]
 
-use common::workqueue::WorkQueue;
+use common::sampler::*;
+use common::workqueue::*;
 use common::*;
-use spirv_std::arch::atomic_i_add;
+use spirv_std::arch::{atomic_i_add, atomic_i_increment, workgroup_memory_barrier_with_group_sync};
 use spirv_std::glam::*;
+use spirv_std::image::SampledImage;
 use spirv_std::ray_tracing::{AccelerationStructure, RayFlags};
 use spirv_std::*;
 
@@ -20,62 +22,56 @@ pub struct RayPayload {
 #[spirv(compute(threads(64)))]
 pub fn generate_camera_rays(
     #[spirv(global_invocation_id)] pos: glam::UVec3,
-    #[spirv(num_workgroups)] size: glam::UVec3,
+    #[spirv(push_constant)] pc: &GenerateCameraRaysPc,
     #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] cameras: &[Camera],
     #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] rays: &mut WorkQueue<RayWorkItem>,
     #[spirv(storage_buffer, descriptor_set = 0, binding = 2)]
     pixel_sample_states: &mut [PixelSampleState],
 ) {
-    assert!(pos.x < size.x);
-    assert!(pos.y < size.y);
-    assert!(pos.z < size.z);
-
-    let idx = size.x * pos.y + pos.x;
-    let wavefront_size = size.x * size.y;
-
-    let sample_pos = pos.as_vec3().xy() / size.as_vec3().xy();
-
-    // cameras[0].near_clip = 0.5;
-    let camera = cameras[0];
-
-    let view2camera = camera.to_view().inverse();
-
-    let near_p = (view2camera * sample_pos.extend(0.).extend(1.)).xyz();
+    let idx = pos.x;
+    let wavefront_size = pc.width * pc.height;
+    if idx >= wavefront_size {
+        return;
+    }
 
-    let o = Vec4::from(camera.to_world[3]).xyz();
-    let d = near_p.normalize();
+    let pixel = uvec2(idx % pc.width, idx / pc.width);
+    // Samples are keyed on the pixel of the film, so a region renders the same as the full film
+    let film_pixel = pixel + uvec2(pc.offset_x, pc.offset_y);
+    let film_size = vec2(pc.film_width as f32, pc.film_height as f32);
 
-    let near_t = camera.near_clip / -d.z;
-    let far_t = camera.far_clip / -d.z;
+    // Jitter the sample position within the pixel
+    let mut sampler = PathSampler::new(
+        pc.sampler,
+        SamplerState::new(film_pixel, pc.sample, pc.seed),
+    );
+    let jitter = sampler.next_2d();
+    let sample_pos = (film_pixel.as_vec2() + jitter) / film_size;
+    let lens_sample = sampler.next_2d();
 
-    let d = -(camera.to_world() * d.extend(0.)).xyz().normalize();
+    let camera = cameras[pc.camera as usize].with_fit(pc.sensor_fit);
+    let (ray, weight) = camera.sample_ray(sample_pos, lens_sample, film_size.x / film_size.y);
 
-    let ray = Ray3f {
-        o: o.extend(1.),
-        d: d.extend(1.),
-        tmin: 0.001,
-        tmax: 10000.,
-        t: 0.,
-    };
-    rays.set(
-        RayWorkItem {
+    // Pixels the camera sees nothing from get no ray and stay black
+    if weight > 0. {
+        rays.push(RayWorkItem {
             ray,
-            throughput: vec4(1., 1., 1., 1.),
+            throughput: Vec3::splat(weight).extend(1.),
             pixel_idx: idx,
-        },
-        idx,
-        wavefront_size,
-    );
+            depth: 0,
+            bsdf_pdf: 0.,
+            sampler: sampler.state,
+        });
+    }
     pixel_sample_states[idx as usize] = PixelSampleState {
-        pixel: pos.xy(),
-        radiance: vec4(1., 0., 0., 0.),
+        pixel,
+        radiance: vec4(0., 0., 0., 0.),
     };
 }
 
 #[spirv(compute(threads(64)))]
 pub fn update_film(
     #[spirv(global_invocation_id)] pos: glam::UVec3,
-    #[spirv(num_workgroups)] size: glam::UVec3,
+    #[spirv(push_constant)] pc: &UpdateFilmPc,
     #[spirv(storage_buffer, descriptor_set = 0, binding = 0)]
     pixel_sample_states: &[PixelSampleState],
     #[spirv(uniform_constant, descriptor_set = 0, binding = 1)] image: &Image!(
@@ -83,32 +79,380 @@ pub fn update_film(
         format = rgba32f,
         sampled = false
     ),
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] accum: &mut [Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] accum_sq: &mut [Vec4],
 ) {
-    assert!(pos.x < size.x);
-    assert!(pos.y < size.y);
-    assert!(pos.z < size.z);
-
-    let idx = size.x * pos.y + pos.x;
-    let wavefront_size = size.x * size.y;
+    let idx = pos.x;
+    if idx as usize >= pixel_sample_states.len() {
+        return;
+    }
 
     let PixelSampleState { pixel, radiance } = pixel_sample_states[idx as usize];
 
-    unsafe { image.write(pixel, radiance.xyz().extend(1.)) };
+    // The first sample overwrites whatever is left in the accumulation buffers
+    let (sum, sum_sq) = if pc.sample == 0 {
+        (radiance, radiance * radiance)
+    } else {
+        (
+            accum[idx as usize] + radiance,
+            accum_sq[idx as usize] + radiance * radiance,
+        )
+    };
+    accum[idx as usize] = sum;
+    accum_sq[idx as usize] = sum_sq;
+
+    let mean = sum / (pc.sample + 1) as f32;
+
+    unsafe { image.write(pixel, mean.xyz().extend(1.)) };
+}
+
+///
+/// Computes the variance of the running mean from the accumulation buffers.
+///
+#[spirv(compute(threads(64)))]
+pub fn film_variance(
+    #[spirv(global_invocation_id)] pos: glam::UVec3,
+    #[spirv(push_constant)] pc: &UpdateFilmPc,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] accum: &[Vec4],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] accum_sq: &[Vec4],
+    #[spirv(uniform_constant, descriptor_set = 0, binding = 2)] variance: &Image!(
+        2D,
+        format = rgba32f,
+        sampled = false
+    ),
+) {
+    let idx = pos.x;
+    if idx as usize >= accum.len() {
+        return;
+    }
+    let pixel = uvec2(idx % pc.width, idx / pc.width);
+
+    let n = pc.sample as f32;
+    let mean = accum[idx as usize] / n;
+    let var = if pc.sample > 1 {
+        (accum_sq[idx as usize] / n - mean * mean) / (n - 1.)
+    } else {
+        Vec4::ZERO
+    };
+
+    unsafe { variance.write(pixel, var.xyz().max(Vec3::ZERO).extend(1.)) };
+}
+
+///
+/// Applies the exposure to the film and maps it to displayable values with the ACES filmic
+/// approximation of Narkowicz.
+///
+#[spirv(compute(threads(64)))]
+pub fn display(
+    #[spirv(global_invocation_id)] pos: glam::UVec3,
+    #[spirv(push_constant)] pc: &DisplayPc,
+    #[spirv(uniform_constant, descriptor_set = 0, binding = 0)] film: &Image!(
+        2D,
+        format = rgba32f,
+        sampled = false
+    ),
+    #[spirv(uniform_constant, descriptor_set = 0, binding = 1)] output: &Image!(
+        2D,
+        format = rgba32f,
+        sampled = false
+    ),
+) {
+    let idx = pos.x;
+    if idx >= pc.width * pc.height {
+        return;
+    }
+    let pixel = uvec2(idx % pc.width, idx / pc.width);
+
+    let radiance: Vec4 = film.read(pixel);
+    let x = radiance.xyz() * pc.scale;
+    let mapped = (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+
+    unsafe { output.write(pixel, mapped.clamp(Vec3::ZERO, Vec3::ONE).extend(1.)) };
 }
 
 #[spirv(compute(threads(64)))]
 pub fn sample_bsdf(
     #[spirv(global_invocation_id)] pos: glam::UVec3,
-    #[spirv(num_workgroups)] size: glam::UVec3,
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] input: &mut WorkQueue<
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] material_eval_queue: &WorkQueue<
         MaterialEvalWorkItem,
     >,
-    #[spirv(push_constant)] material: u32,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] rays: &mut WorkQueue<RayWorkItem>,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)]
+    pixel_sample_states: &mut [PixelSampleState],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] instances: &[Instance],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] materials: &[Material],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] emitters: &[Emitter],
+    #[spirv(descriptor_set = 0, binding = 6)] textures: &RuntimeArray<
+        SampledImage<Image!(2D, type=f32, sampled)>,
+    >,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] shadow_rays: &mut WorkQueue<
+        ShadowRayWorkItem,
+    >,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 8)] meshes: &[Mesh],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] indices: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 10)] positions: &[Vec3],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] emitter_cdfs: &[f32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 12)] uvs: &[Vec2],
+    #[spirv(push_constant)] pc: &SampleBsdfPc,
 ) {
-    assert!(pos.x < size.x);
-    assert!(pos.y < size.y);
-    assert!(pos.z < size.z);
-    todo!()
+    if pos.x >= material_eval_queue.len {
+        return;
+    }
+    let MaterialEvalWorkItem {
+        si,
+        throughput,
+        pixel_idx,
+        depth,
+        bsdf_pdf,
+        sampler,
+    } = *material_eval_queue.item(pos.x);
+
+    let mut sampler = PathSampler::new(pc.sampler, sampler);
+    let shading = path::shade_vertex(
+        &si,
+        throughput.xyz(),
+        depth,
+        bsdf_pdf,
+        &mut sampler,
+        pc.mis,
+        pc.max_depth,
+        pc.rr_depth,
+        instances,
+        meshes,
+        indices,
+        positions,
+        uvs,
+        emitters,
+        emitter_cdfs,
+        materials,
+        textures,
+    );
+
+    pixel_sample_states[pixel_idx as usize].radiance += shading.emission.extend(0.);
+    if shading.direct != Vec3::ZERO {
+        shadow_rays.push(ShadowRayWorkItem {
+            ray: shading.shadow_ray,
+            radiance: shading.direct.extend(0.),
+            pixel_idx,
+        });
+    }
+
+    // Killed paths are not pushed to the next queue
+    if shading.continues {
+        rays.push(RayWorkItem {
+            ray: shading.ray,
+            throughput: shading.throughput.extend(1.),
+            pixel_idx,
+            depth: depth + 1,
+            bsdf_pdf: shading.bsdf_pdf,
+            sampler: sampler.state,
+        });
+    }
+}
+
+///
+/// Turns the length of a `WorkQueue` into indirect dispatch arguments.
+/// Only the queue's header is accessed, so it is bound independently of the item type.
+///
+#[spirv(compute(threads(1)))]
+pub fn write_indirect_args(
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] queue: &WorkQueue<u32>,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] args: &mut IndirectArgs,
+) {
+    let len = queue.len;
+    *args = IndirectArgs {
+        dispatch: DispatchIndirectCommand {
+            x: (len + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE,
+            y: 1,
+            z: 1,
+        },
+        trace_rays: TraceRaysIndirectCommand {
+            width: len,
+            height: 1,
+            depth: 1,
+        },
+    };
+}
+
+///
+/// Counts the items of the material evaluation queue per material.
+/// First pass of the counting sort performed by `scan_material_counts` and `scatter_materials`.
+///
+#[spirv(compute(threads(64)))]
+pub fn count_materials(
+    #[spirv(global_invocation_id)] pos: UVec3,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] material_eval_queue: &WorkQueue<
+        MaterialEvalWorkItem,
+    >,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] counts: &mut [u32],
+) {
+    let idx = pos.x;
+    if idx >= material_eval_queue.len {
+        return;
+    }
+    let material = material_eval_queue[idx].si.material;
+    unsafe {
+        atomic_i_increment::<u32, 1, 0>(&mut counts[material as usize]);
+    }
+}
+
+///
+/// Turns the per material counts into the offsets of their ranges in the sorted queue.
+///
+#[spirv(compute(threads(1)))]
+pub fn scan_material_counts(
+    #[spirv(push_constant)] pc: &SortMaterialsPc,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] counts: &mut [u32],
+) {
+    let mut offset = 0;
+    let mut i = 0;
+    while i < pc.material_count {
+        let count = counts[i as usize];
+        counts[i as usize] = offset;
+        offset += count;
+        i += 1;
+    }
+}
+
+///
+/// Moves the items of the material evaluation queue into the range of their material in
+/// `sorted`. The order within a range is not specified.
+///
+#[spirv(compute(threads(64)))]
+pub fn scatter_materials(
+    #[spirv(global_invocation_id)] pos: UVec3,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] material_eval_queue: &WorkQueue<
+        MaterialEvalWorkItem,
+    >,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] offsets: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] sorted: &mut WorkQueue<
+        MaterialEvalWorkItem,
+    >,
+) {
+    let idx = pos.x;
+    let len = material_eval_queue.len;
+    if idx >= len {
+        return;
+    }
+    let item = material_eval_queue[idx];
+    let slot = unsafe { atomic_i_increment::<u32, 1, 0>(&mut offsets[item.si.material as usize]) };
+    sorted.set(item, slot, len);
+}
+
+///
+/// Exclusive prefix sum of `values` within each workgroup into `scanned`, the total of every
+/// workgroup is written to `block_sums`.
+/// Larger arrays are scanned by scanning `block_sums` and adding them with
+/// `scan_add_block_sums`.
+///
+#[spirv(compute(threads(64)))]
+pub fn scan_blocks(
+    #[spirv(global_invocation_id)] pos: UVec3,
+    #[spirv(local_invocation_id)] local: UVec3,
+    #[spirv(workgroup_id)] group: UVec3,
+    #[spirv(push_constant)] pc: &ScanPc,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] values: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] scanned: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] block_sums: &mut [u32],
+    #[spirv(workgroup)] shared: &mut [u32; WORKGROUP_SIZE as usize],
+) {
+    let idx = pos.x;
+    let lid = local.x as usize;
+
+    let value = if idx < pc.count {
+        values[idx as usize]
+    } else {
+        0
+    };
+    shared[lid] = value;
+    unsafe { workgroup_memory_barrier_with_group_sync() };
+
+    // Hillis-Steele scan, all threads have to reach the barriers
+    let mut offset = 1;
+    while offset < WORKGROUP_SIZE as usize {
+        let add = if lid >= offset {
+            shared[lid - offset]
+        } else {
+            0
+        };
+        unsafe { workgroup_memory_barrier_with_group_sync() };
+        shared[lid] += add;
+        unsafe { workgroup_memory_barrier_with_group_sync() };
+        offset *= 2;
+    }
+
+    if idx < pc.count {
+        scanned[idx as usize] = shared[lid] - value;
+    }
+    let last = WORKGROUP_SIZE as usize - 1;
+    if lid == last {
+        block_sums[group.x as usize] = shared[last];
+    }
+}
+
+#[spirv(compute(threads(64)))]
+pub fn scan_add_block_sums(
+    #[spirv(global_invocation_id)] pos: UVec3,
+    #[spirv(workgroup_id)] group: UVec3,
+    #[spirv(push_constant)] pc: &ScanPc,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] values: &mut [u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] block_sums: &[u32],
+) {
+    let idx = pos.x;
+    if idx >= pc.count {
+        return;
+    }
+    values[idx as usize] += block_sums[group.x as usize];
+}
+
+///
+/// Stable partition of the items in `src` by `flags`, given the exclusive prefix sum of the
+/// flags in `offsets`.
+/// Accepted items are moved to the front of `dst` in their original order, rejected ones follow
+/// them or are dropped. Items are copied as raw words so the kernel works for any item type.
+///
+#[spirv(compute(threads(64)))]
+pub fn partition_items(
+    #[spirv(global_invocation_id)] pos: UVec3,
+    #[spirv(push_constant)] pc: &PartitionPc,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] src: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] flags: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] offsets: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] dst: &mut [u32],
+) {
+    let header = HEADER_WORDS;
+    let (len, src_base) = if pc.src_queue != 0 {
+        (src[LEN_WORD as usize], header)
+    } else {
+        (pc.count, 0)
+    };
+    let dst_base = if pc.dst_queue != 0 { header } else { 0 };
+
+    let idx = pos.x;
+    if idx >= len {
+        return;
+    }
+
+    let accepted = offsets[len as usize - 1] + flags[len as usize - 1];
+    if idx == 0 && pc.dst_queue != 0 {
+        dst[LEN_WORD as usize] = if pc.keep_rejected != 0 { len } else { accepted };
+    }
+
+    let slot = if flags[idx as usize] != 0 {
+        offsets[idx as usize]
+    } else if pc.keep_rejected != 0 {
+        accepted + idx - offsets[idx as usize]
+    } else {
+        return;
+    };
+
+    let src_start = (src_base + idx * pc.stride) as usize;
+    let dst_start = (dst_base + slot * pc.stride) as usize;
+    let mut i = 0;
+    while i < pc.stride as usize {
+        dst[dst_start + i] = src[src_start + i];
+        i += 1;
+    }
 }
 
 #[spirv(ray_generation)]
@@ -122,15 +466,21 @@ pub fn intersect_closest(
         MaterialEvalWorkItem,
     >,
     #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] instances: &[Instance],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] meshes: &[Mesh],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] indices: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] normals: &[Vec3],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] uvs: &[Vec2],
 ) {
     assert!(pos.x < size.x);
     assert!(pos.y < size.y);
     assert!(pos.z < size.z);
-    // let WorkItem { item: ray, idx } = *rays.item(pos.x);
     let RayWorkItem {
         ray,
         throughput,
         pixel_idx,
+        depth,
+        bsdf_pdf,
+        sampler,
     } = *rays.item(pos.x);
 
     *payload = RayPayload::default();
@@ -151,20 +501,246 @@ pub fn intersect_closest(
     };
 
     if payload.valid != 0 {
-        let material = instances[payload.instance as usize].material;
         material_eval_queue.push(MaterialEvalWorkItem {
+            si: SurfaceInteraction::from_hit(
+                &ray,
+                payload.instance,
+                payload.primitive,
+                payload.uv,
+                payload.dist,
+                instances,
+                meshes,
+                indices,
+                normals,
+                uvs,
+            ),
+            throughput,
             pixel_idx,
-            si: SurfaceInteraction {
-                p: (ray.o.xyz() + ray.d.xyz() * payload.dist).extend(1.),
-                dist: payload.dist,
-                t: ray.t,
-                instance: payload.instance,
-                primitive: payload.primitive,
-                material,
-            },
+            depth,
+            bsdf_pdf,
+            sampler,
         });
     }
 }
+
+#[spirv(ray_generation)]
+pub fn intersect_any(
+    #[spirv(ray_payload)] payload: &mut RayPayload,
+    #[spirv(launch_id)] pos: UVec3,
+    #[spirv(launch_size)] size: UVec3,
+    #[spirv(uniform_constant, descriptor_set = 0, binding = 0)] accel: &AccelerationStructure,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] shadow_rays: &WorkQueue<
+        ShadowRayWorkItem,
+    >,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)]
+    pixel_sample_states: &mut [PixelSampleState],
+) {
+    assert!(pos.x < size.x);
+    let ShadowRayWorkItem {
+        ray,
+        radiance,
+        pixel_idx,
+    } = *shadow_rays.item(pos.x);
+
+    // Occluded unless the shadow miss shader is invoked
+    *payload = RayPayload::default();
+    payload.valid = 1;
+
+    unsafe {
+        accel.trace_ray(
+            RayFlags::OPAQUE | RayFlags::TERMINATE_ON_FIRST_HIT | RayFlags::SKIP_CLOSEST_HIT_SHADER,
+            0xff,
+            0,
+            0,
+            1,
+            ray.o.xyz(),
+            ray.tmin,
+            ray.d.xyz(),
+            ray.tmax,
+            payload,
+        )
+    };
+
+    if payload.valid == 0 {
+        pixel_sample_states[pixel_idx as usize].radiance += radiance;
+    }
+}
+
+///
+/// Megakernel path tracer, tracing all samples of a pixel in one invocation.
+/// Writes the mean radiance and the position and shading normal of the first hit.
+///
+#[spirv(ray_generation)]
+pub fn path_trace(
+    #[spirv(ray_payload)] payload: &mut RayPayload,
+    #[spirv(launch_id)] pos: UVec3,
+    #[spirv(push_constant)] pc: &PathTracePc,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] indices: &[u32],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 1)] positions: &[Vec3],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] normals: &[Vec3],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 3)] uvs: &[Vec2],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] instances: &[Instance],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] meshes: &[Mesh],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] emitters: &[Emitter],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] materials: &[Material],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 8)] cameras: &[Camera],
+    #[spirv(descriptor_set = 0, binding = 9)] textures: &RuntimeArray<
+        SampledImage<Image!(2D, type=f32, sampled)>,
+    >,
+    #[spirv(uniform_constant, descriptor_set = 0, binding = 10)] accel: &AccelerationStructure,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] emitter_cdfs: &[f32],
+    #[spirv(uniform_constant, descriptor_set = 1, binding = 0)] color: &Image!(
+        2D,
+        format = rgba32f,
+        sampled = false
+    ),
+    #[spirv(uniform_constant, descriptor_set = 1, binding = 1)] normal: &Image!(
+        2D,
+        format = rgba32f,
+        sampled = false
+    ),
+    #[spirv(uniform_constant, descriptor_set = 1, binding = 2)] position: &Image!(
+        2D,
+        format = rgba32f,
+        sampled = false
+    ),
+) {
+    let pixel = pos.xy();
+    let film_pixel = pixel + uvec2(pc.offset_x, pc.offset_y);
+    let film_size = vec2(pc.film_width as f32, pc.film_height as f32);
+    let camera = cameras[pc.camera as usize].with_fit(pc.sensor_fit);
+
+    let mut radiance_sum = Vec3::ZERO;
+    let mut first_normal = Vec3::ZERO;
+    let mut first_position = Vec3::ZERO;
+
+    let mut sample = 0;
+    while sample < pc.spp {
+        let mut sampler = PathSampler::new(
+            pc.sampler,
+            SamplerState::new(film_pixel, pc.sample_offset + sample, pc.seed),
+        );
+        let jitter = sampler.next_2d();
+        let sample_pos = (film_pixel.as_vec2() + jitter) / film_size;
+        let lens_sample = sampler.next_2d();
+
+        let (mut ray, weight) =
+            camera.sample_ray(sample_pos, lens_sample, film_size.x / film_size.y);
+        let mut throughput = Vec3::splat(weight);
+        let mut bsdf_pdf = 0.;
+        let mut radiance = Vec3::ZERO;
+
+        // Rays the camera sees nothing along are not traced and add black
+        let mut depth = 0;
+        while weight > 0. && depth < pc.max_depth {
+            *payload = RayPayload::default();
+            unsafe {
+                accel.trace_ray(
+                    RayFlags::OPAQUE,
+                    0xff,
+                    0,
+                    0,
+                    0,
+                    ray.o.xyz(),
+                    ray.tmin,
+                    ray.d.xyz(),
+                    ray.tmax,
+                    payload,
+                )
+            };
+            if payload.valid == 0 {
+                break;
+            }
+            let si = SurfaceInteraction::from_hit(
+                &ray,
+                payload.instance,
+                payload.primitive,
+                payload.uv,
+                payload.dist,
+                instances,
+                meshes,
+                indices,
+                normals,
+                uvs,
+            );
+            if depth == 0 && sample == 0 {
+                first_normal = si.n.xyz();
+                first_position = si.p.xyz();
+            }
+
+            let shading = path::shade_vertex(
+                &si,
+                throughput,
+                depth,
+                bsdf_pdf,
+                &mut sampler,
+                pc.mis,
+                pc.max_depth,
+                pc.rr_depth,
+                instances,
+                meshes,
+                indices,
+                positions,
+                uvs,
+                emitters,
+                emitter_cdfs,
+                materials,
+                textures,
+            );
+            radiance += shading.emission;
+
+            if shading.direct != Vec3::ZERO {
+                // Occluded unless the shadow miss shader is invoked
+                let shadow_ray = shading.shadow_ray;
+                *payload = RayPayload::default();
+                payload.valid = 1;
+                unsafe {
+                    accel.trace_ray(
+                        RayFlags::OPAQUE
+                            | RayFlags::TERMINATE_ON_FIRST_HIT
+                            | RayFlags::SKIP_CLOSEST_HIT_SHADER,
+                        0xff,
+                        0,
+                        0,
+                        1,
+                        shadow_ray.o.xyz(),
+                        shadow_ray.tmin,
+                        shadow_ray.d.xyz(),
+                        shadow_ray.tmax,
+                        payload,
+                    )
+                };
+                if payload.valid == 0 {
+                    radiance += shading.direct;
+                }
+            }
+
+            if !shading.continues {
+                break;
+            }
+            ray = shading.ray;
+            throughput = shading.throughput;
+            bsdf_pdf = shading.bsdf_pdf;
+            depth += 1;
+        }
+
+        radiance_sum += radiance;
+        sample += 1;
+    }
+
+    // Continue the mean of the samples of previous launches
+    if pc.sample_offset > 0 {
+        let prev: Vec4 = color.read(pixel);
+        radiance_sum += prev.xyz() * pc.sample_offset as f32;
+    }
+    let mean = radiance_sum / (pc.sample_offset + pc.spp).max(1) as f32;
+    unsafe {
+        color.write(pixel, mean.extend(1.));
+        normal.write(pixel, first_normal.extend(0.));
+        position.write(pixel, first_position.extend(1.));
+    }
+}
+
 #[spirv(closest_hit)]
 #[allow(unused_variables)]
 pub fn rchit(
@@ -185,4 +761,6 @@ pub fn rchit(
 pub fn rmiss(#[spirv(incoming_ray_payload)] payload: &mut RayPayload) {}
 //
 #[spirv(miss)]
-pub fn rmiss_shadow(#[spirv(incoming_ray_payload)] payload: &mut RayPayload) {}
+pub fn rmiss_shadow(#[spirv(incoming_ray_payload)] payload: &mut RayPayload) {
+    payload.valid = 0;
+}
@@ -166,6 +166,19 @@ impl<T: Sized + Copy> Array<T> {
             _ty: PhantomData,
         }
     }
+    ///
+    /// Records a copy into a host-visible array, which can be mapped once the graph has been
+    /// submitted and finished. The array has to be created with `TRANSFER_SRC` usage.
+    ///
+    pub fn readback(&self, device: &Arc<Device>, graph: &mut RenderGraph) -> Self {
+        let array = Self::empty(device, self.count);
+
+        let src = graph.bind_node(&self.buf);
+        let dst = graph.bind_node(&array.buf);
+        graph.copy_buffer(src, dst);
+
+        array
+    }
     pub fn buf(&self) -> &Arc<Buffer> {
         &self.buf
     }
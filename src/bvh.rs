@@ -0,0 +1,578 @@
+use common::Ray3f;
+use glam::*;
+
+use crate::scene::Scene;
+
+///
+/// Closest intersection of a ray with the scene, in the form the ray tracing pipeline reports
+/// it to `rchit`.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Hit {
+    pub instance: u32,
+    pub primitive: u32,
+    pub barycentric: Vec2,
+    pub dist: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Aabb {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Aabb {
+    const EMPTY: Self = Self {
+        min: Vec3::splat(f32::INFINITY),
+        max: Vec3::splat(f32::NEG_INFINITY),
+    };
+    fn union(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+    fn grow(self, p: Vec3) -> Self {
+        Self {
+            min: self.min.min(p),
+            max: self.max.max(p),
+        }
+    }
+    fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+    fn area(&self) -> f32 {
+        let e = (self.max - self.min).max(Vec3::ZERO);
+        2. * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+    fn transform(&self, m: &Mat4) -> Self {
+        let mut aabb = Self::EMPTY;
+        for i in 0..8 {
+            let corner = vec3(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            aabb = aabb.grow(m.transform_point3(corner));
+        }
+        aabb
+    }
+    ///
+    /// Slab test, returns the entry distance if the box is hit within `[0, tmax]`.
+    ///
+    fn intersect(&self, o: Vec3, inv_d: Vec3, tmax: f32) -> Option<f32> {
+        let t0 = (self.min - o) * inv_d;
+        let t1 = (self.max - o) * inv_d;
+        let tnear = t0.min(t1).max_element();
+        let tfar = t0.max(t1).min_element();
+        (tnear <= tfar && tfar >= 0. && tnear <= tmax).then_some(tnear)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    aabb: Aabb,
+    /// Index of the left child for inner nodes, of the first primitive for leaves.
+    first: u32,
+    /// Number of primitives, 0 for inner nodes.
+    count: u32,
+}
+
+const BINS: usize = 16;
+const MAX_LEAF_SIZE: usize = 4;
+/// Cost of traversing a node relative to intersecting a primitive.
+const TRAVERSAL_COST: f32 = 1.;
+
+///
+/// Bounding volume hierarchy over abstract primitives given by their bounds, built with binned
+/// SAH. Traversal orders primitives through `order`.
+///
+#[derive(Debug, Default)]
+struct Tree {
+    nodes: Vec<Node>,
+    order: Vec<u32>,
+}
+
+impl Tree {
+    fn build(bounds: &[Aabb]) -> Self {
+        let mut tree = Self {
+            nodes: vec![],
+            order: (0..bounds.len() as u32).collect(),
+        };
+        if !bounds.is_empty() {
+            tree.nodes.push(tree.node(bounds, 0, bounds.len()));
+            tree.split(bounds, 0);
+        }
+        tree
+    }
+    fn node(&self, bounds: &[Aabb], first: usize, count: usize) -> Node {
+        let aabb = self.order[first..first + count]
+            .iter()
+            .fold(Aabb::EMPTY, |aabb, &i| aabb.union(bounds[i as usize]));
+        Node {
+            aabb,
+            first: first as u32,
+            count: count as u32,
+        }
+    }
+    fn split(&mut self, bounds: &[Aabb], node: usize) {
+        let Node { aabb, first, count } = self.nodes[node];
+        let (first, count) = (first as usize, count as usize);
+        if count <= 1 {
+            return;
+        }
+
+        let centroid_bounds = self.order[first..first + count]
+            .iter()
+            .fold(Aabb::EMPTY, |aabb, &i| {
+                aabb.grow(bounds[i as usize].centroid())
+            });
+
+        // Find the cheapest split among the bin boundaries of all axes
+        let mut best: Option<(usize, usize, f32)> = None;
+        for axis in 0..3 {
+            let min = centroid_bounds.min[axis];
+            let extent = centroid_bounds.max[axis] - min;
+            if extent <= 0. {
+                continue;
+            }
+            let bin_of = |aabb: &Aabb| {
+                (((aabb.centroid()[axis] - min) / extent * BINS as f32) as usize).min(BINS - 1)
+            };
+
+            let mut bins = [(Aabb::EMPTY, 0usize); BINS];
+            for &i in &self.order[first..first + count] {
+                let bin = &mut bins[bin_of(&bounds[i as usize])];
+                bin.0 = bin.0.union(bounds[i as usize]);
+                bin.1 += 1;
+            }
+
+            let mut right_area = [0.; BINS];
+            let mut right = (Aabb::EMPTY, 0);
+            for b in (1..BINS).rev() {
+                right = (right.0.union(bins[b].0), right.1 + bins[b].1);
+                right_area[b] = right.0.area() * right.1 as f32;
+            }
+            let mut left = (Aabb::EMPTY, 0);
+            for b in 1..BINS {
+                left = (left.0.union(bins[b - 1].0), left.1 + bins[b - 1].1);
+                let cost = left.0.area() * left.1 as f32 + right_area[b];
+                if left.1 > 0 && left.1 < count && best.map_or(true, |(_, _, c)| cost < c) {
+                    best = Some((axis, b, cost));
+                }
+            }
+        }
+
+        let leaf_cost = aabb.area() * count as f32;
+        let (axis, bin, cost) = match best {
+            Some(best) => best,
+            None => return,
+        };
+        if count <= MAX_LEAF_SIZE && TRAVERSAL_COST * aabb.area() + cost >= leaf_cost {
+            return;
+        }
+
+        // Partition the primitives by their bin
+        let min = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - min;
+        let (mut i, mut j) = (first, first + count);
+        while i < j {
+            let centroid = bounds[self.order[i] as usize].centroid()[axis];
+            let b = (((centroid - min) / extent * BINS as f32) as usize).min(BINS - 1);
+            if b < bin {
+                i += 1;
+            } else {
+                j -= 1;
+                self.order.swap(i, j);
+            }
+        }
+        let mid = i - first;
+
+        let left = self.nodes.len();
+        self.nodes.push(self.node(bounds, first, mid));
+        self.nodes.push(self.node(bounds, first + mid, count - mid));
+        self.nodes[node].first = left as u32;
+        self.nodes[node].count = 0;
+
+        self.split(bounds, left);
+        self.split(bounds, left + 1);
+    }
+    ///
+    /// Calls `intersect` with the primitives of all leaves hit by the ray, nearer children
+    /// first. `intersect` returns the new maximum distance and whether to stop traversal.
+    ///
+    fn traverse(
+        &self,
+        o: Vec3,
+        d: Vec3,
+        tmax: f32,
+        mut intersect: impl FnMut(u32, f32) -> (f32, bool),
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let inv_d = d.recip();
+        let mut tmax = tmax;
+        let mut stack = vec![0usize];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if node.aabb.intersect(o, inv_d, tmax).is_none() {
+                continue;
+            }
+            if node.count == 0 {
+                let left = node.first as usize;
+                let right = left + 1;
+                let tl = self.nodes[left].aabb.intersect(o, inv_d, tmax);
+                let tr = self.nodes[right].aabb.intersect(o, inv_d, tmax);
+                // Push the farther child first, so the nearer one is visited first
+                match (tl, tr) {
+                    (Some(tl), Some(tr)) if tl < tr => stack.extend([right, left]),
+                    (Some(_), Some(_)) => stack.extend([left, right]),
+                    (Some(_), None) => stack.push(left),
+                    (None, Some(_)) => stack.push(right),
+                    (None, None) => {}
+                }
+                continue;
+            }
+            let first = node.first as usize;
+            for &primitive in &self.order[first..first + node.count as usize] {
+                let (t, stop) = intersect(primitive, tmax);
+                tmax = t;
+                if stop {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+///
+/// Möller-Trumbore intersection, returns the distance and the barycentric coordinates of `p1`
+/// and `p2`.
+///
+fn intersect_triangle(
+    p0: Vec3,
+    p1: Vec3,
+    p2: Vec3,
+    o: Vec3,
+    d: Vec3,
+    tmin: f32,
+    tmax: f32,
+) -> Option<(f32, Vec2)> {
+    let e1 = p1 - p0;
+    let e2 = p2 - p0;
+    let p = d.cross(e2);
+    let det = e1.dot(p);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let inv_det = 1. / det;
+    let t = o - p0;
+    let u = t.dot(p) * inv_det;
+    if !(0. ..=1.).contains(&u) {
+        return None;
+    }
+    let q = t.cross(e1);
+    let v = d.dot(q) * inv_det;
+    if v < 0. || u + v > 1. {
+        return None;
+    }
+    let dist = e2.dot(q) * inv_det;
+    if dist < tmin || dist > tmax {
+        return None;
+    }
+    Some((dist, vec2(u, v)))
+}
+
+///
+/// Bottom level of the hierarchy over the triangles of one mesh in object space.
+///
+struct MeshBvh {
+    tree: Tree,
+    triangles: Vec<[Vec3; 3]>,
+}
+
+impl MeshBvh {
+    fn new(scene: &Scene, mesh: usize) -> Self {
+        let mesh = &scene.meshes[mesh];
+        let triangles = (0..mesh.indices_count as usize / 3)
+            .map(|primitive| {
+                let idx = mesh.indices as usize + 3 * primitive;
+                [0, 1, 2].map(|j| {
+                    scene.positions[mesh.positions as usize + scene.indices[idx + j] as usize]
+                })
+            })
+            .collect::<Vec<_>>();
+        let bounds = triangles
+            .iter()
+            .map(|t| Aabb::EMPTY.grow(t[0]).grow(t[1]).grow(t[2]))
+            .collect::<Vec<_>>();
+        Self {
+            tree: Tree::build(&bounds),
+            triangles,
+        }
+    }
+    fn aabb(&self) -> Aabb {
+        self.tree
+            .nodes
+            .first()
+            .map_or(Aabb::EMPTY, |node| node.aabb)
+    }
+}
+
+///
+/// Two-level bounding volume hierarchy over the scene, mirroring the acceleration structures
+/// built on the GPU: one bottom level per mesh and a top level over the instances.
+/// Both levels are built with binned SAH.
+///
+pub struct Bvh {
+    meshes: Vec<MeshBvh>,
+    instances: Vec<(u32, Mat4)>, // Mesh and world to object transform
+    tree: Tree,
+}
+
+impl Bvh {
+    pub fn new(scene: &Scene) -> Self {
+        let meshes = (0..scene.meshes.len())
+            .map(|mesh| MeshBvh::new(scene, mesh))
+            .collect::<Vec<_>>();
+        let bounds = scene
+            .instances
+            .iter()
+            .map(|instance| {
+                meshes[instance.mesh as usize]
+                    .aabb()
+                    .transform(&instance.to_world)
+            })
+            .collect::<Vec<_>>();
+        Self {
+            instances: scene
+                .instances
+                .iter()
+                .map(|instance| (instance.mesh, instance.to_world.inverse()))
+                .collect(),
+            tree: Tree::build(&bounds),
+            meshes,
+        }
+    }
+    fn traverse(&self, ray: &Ray3f, any: bool) -> Option<Hit> {
+        let o = ray.o.xyz();
+        let d = ray.d.xyz();
+
+        let mut closest: Option<Hit> = None;
+        self.tree.traverse(o, d, ray.tmax, |instance, tmax| {
+            let (mesh, to_object) = &self.instances[instance as usize];
+            let mesh = &self.meshes[*mesh as usize];
+
+            // The direction is not normalized, so distances are the same in both spaces
+            let o = to_object.transform_point3(o);
+            let d = to_object.transform_vector3(d);
+
+            let mut tmax = tmax;
+            let mut stop = false;
+            mesh.tree.traverse(o, d, tmax, |primitive, t| {
+                let [p0, p1, p2] = mesh.triangles[primitive as usize];
+                match intersect_triangle(p0, p1, p2, o, d, ray.tmin, t) {
+                    Some((dist, barycentric)) => {
+                        tmax = dist;
+                        stop = any;
+                        closest = Some(Hit {
+                            instance,
+                            primitive,
+                            barycentric,
+                            dist,
+                        });
+                        (dist, any)
+                    }
+                    None => (t, false),
+                }
+            });
+            (tmax, stop)
+        });
+        closest
+    }
+    ///
+    /// Closest hit along the ray within `[tmin, tmax]`.
+    ///
+    pub fn intersect_closest(&self, ray: &Ray3f) -> Option<Hit> {
+        self.traverse(ray, false)
+    }
+    ///
+    /// Whether anything is hit within `[tmin, tmax]`, terminating on the first hit.
+    ///
+    pub fn intersect_any(&self, ray: &Ray3f) -> bool {
+        self.traverse(ray, true).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::rand::Rng;
+    use common::{Instance, Mesh};
+
+    fn next_vec3(rng: &mut Rng) -> Vec3 {
+        vec3(rng.next_f32(), rng.next_f32(), rng.next_f32())
+    }
+
+    ///
+    /// Scene of three meshes of random triangles, each instanced several times with random
+    /// rotations, non-uniform scales and translations.
+    ///
+    fn random_scene(seed: u32) -> Scene {
+        let mut rng = Rng::new(seed);
+        let mut scene = Scene::default();
+        for triangle_count in [1, 50, 300] {
+            let mesh = Mesh {
+                indices: scene.indices.len() as u32,
+                indices_count: 3 * triangle_count,
+                positions: scene.positions.len() as u32,
+                normals: 0,
+                uvs: 0,
+            };
+            for i in 0..3 * triangle_count {
+                // Small triangles scattered in the unit cube
+                let p = if i % 3 == 0 {
+                    next_vec3(&mut rng) * 2. - Vec3::ONE
+                } else {
+                    scene.positions[scene.positions.len() - 1] + next_vec3(&mut rng) * 0.3
+                };
+                scene.positions.push(p);
+                scene.indices.push(i);
+            }
+            scene.meshes.push(mesh);
+        }
+        for i in 0..12 {
+            let axis = (next_vec3(&mut rng) - Vec3::splat(0.5)).normalize();
+            scene.instances.push(Instance {
+                to_world: Mat4::from_scale_rotation_translation(
+                    next_vec3(&mut rng) * 1.5 + Vec3::splat(0.5),
+                    Quat::from_axis_angle(axis, rng.next_f32() * 6.),
+                    next_vec3(&mut rng) * 8. - Vec3::splat(4.),
+                ),
+                mesh: i % 3,
+                material: 0,
+                emitter: -1,
+            });
+        }
+        scene
+    }
+
+    fn random_ray(rng: &mut Rng) -> Ray3f {
+        let o = next_vec3(rng) * 12. - Vec3::splat(6.);
+        let target = next_vec3(rng) * 8. - Vec3::splat(4.);
+        // Some rays end before they reach the scene
+        let tmax = if rng.next_f32() < 0.5 {
+            10000.
+        } else {
+            rng.next_f32() * 10.
+        };
+        Ray3f {
+            o: o.extend(1.),
+            d: (target - o).normalize().extend(0.),
+            tmin: 0.001,
+            tmax,
+            t: 0.,
+        }
+    }
+
+    ///
+    /// All hits found by testing every triangle of every instance in world space, closest first.
+    ///
+    fn brute_force_hits(scene: &Scene, ray: &Ray3f) -> Vec<Hit> {
+        let mut hits = vec![];
+        for (instance_idx, instance) in scene.instances.iter().enumerate() {
+            let mesh = &scene.meshes[instance.mesh as usize];
+            for primitive in 0..mesh.indices_count / 3 {
+                let idx = mesh.indices as usize + 3 * primitive as usize;
+                let [p0, p1, p2] = [0, 1, 2].map(|j| {
+                    let position =
+                        scene.positions[mesh.positions as usize + scene.indices[idx + j] as usize];
+                    instance.to_world.transform_point3(position)
+                });
+                if let Some((dist, barycentric)) =
+                    intersect_triangle(p0, p1, p2, ray.o.xyz(), ray.d.xyz(), ray.tmin, ray.tmax)
+                {
+                    hits.push(Hit {
+                        instance: instance_idx as u32,
+                        primitive,
+                        barycentric,
+                        dist,
+                    });
+                }
+            }
+        }
+        hits.sort_by(|a, b| a.dist.total_cmp(&b.dist));
+        hits
+    }
+
+    fn brute_force(scene: &Scene, ray: &Ray3f) -> Option<Hit> {
+        brute_force_hits(scene, ray).first().copied()
+    }
+
+    #[test]
+    fn closest_hit_matches_brute_force() {
+        for seed in 0..4 {
+            let scene = random_scene(seed);
+            let bvh = Bvh::new(&scene);
+            let mut rng = Rng::new(seed + 100);
+            let mut hits = 0;
+            for _ in 0..2000 {
+                let ray = random_ray(&mut rng);
+                let expected = brute_force_hits(&scene, &ray);
+                match (bvh.intersect_closest(&ray), expected.first()) {
+                    (None, None) => {}
+                    (Some(hit), Some(closest)) => {
+                        hits += 1;
+                        assert!(
+                            (hit.dist - closest.dist).abs() < 1e-3,
+                            "{hit:?} {closest:?}"
+                        );
+                        // Triangles hit at almost the same distance may be resolved either way
+                        let ambiguous = expected
+                            .get(1)
+                            .map_or(false, |second| second.dist - closest.dist < 1e-4);
+                        if !ambiguous {
+                            assert_eq!(hit.instance, closest.instance, "{ray:?}");
+                            assert_eq!(hit.primitive, closest.primitive, "{ray:?}");
+                            assert!(
+                                (hit.barycentric - closest.barycentric).length() < 1e-3,
+                                "{hit:?} {closest:?}"
+                            );
+                        }
+                    }
+                    (hit, closest) => panic!("{ray:?}: {hit:?} != {closest:?}"),
+                }
+            }
+            // The rays have to actually exercise the hierarchy
+            assert!(hits > 200, "only {hits} hits");
+        }
+    }
+
+    #[test]
+    fn any_hit_matches_brute_force() {
+        for seed in 0..4 {
+            let scene = random_scene(seed);
+            let bvh = Bvh::new(&scene);
+            let mut rng = Rng::new(seed + 200);
+            for _ in 0..2000 {
+                let ray = random_ray(&mut rng);
+                assert_eq!(
+                    bvh.intersect_any(&ray),
+                    brute_force(&scene, &ray).is_some(),
+                    "{ray:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn empty_scene() {
+        let scene = Scene::default();
+        let bvh = Bvh::new(&scene);
+        let mut rng = Rng::new(0);
+        let ray = random_ray(&mut rng);
+        assert!(bvh.intersect_closest(&ray).is_none());
+        assert!(!bvh.intersect_any(&ray));
+    }
+}
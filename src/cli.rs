@@ -0,0 +1,383 @@
+use anyhow::{anyhow, bail, Context};
+use common::SensorFit;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::settings::{CropWindow, OutputBuffer, OutputTarget, RenderSettings};
+
+pub const USAGE: &str = "\
+Usage: test-wf [OPTIONS] [SCENE]
+
+Renders the glTF file SCENE (default: assets/cornell-box.gltf).
+
+Options:
+  -c, --config <FILE>          Read the render settings from a JSON file, other options
+                               override its values
+  -o, --output [BUFFER=]<FILE> Write BUFFER (color, variance, normal or position, default:
+                               color) to FILE, can be repeated (default: out/img.exr)
+      --width <PIXELS>         Width of the image
+      --height <PIXELS>        Height of the image
+      --crop <X,Y,W,H>         Render only a region of the image
+      --sensor-fit <FIT>       horizontal, vertical, fill or overscan, how the sensor of the
+                               camera is fit to the image (default: the fit of the camera)
+      --spp <N>                Samples per pixel
+      --camera <INDEX>         Camera of the scene to render from
+      --integrator <NAME>      wavefront, wavefront_ray_query, megakernel or reference (on
+                               the CPU)
+      --seed <N>               Seed of the samplers
+      --max-depth <N>          Maximum number of bounces
+      --rr-depth <N>           Depth at which russian roulette starts
+      --headless               Render to the outputs without opening the viewer
+      --debug                  Enable the Vulkan validation layers
+  -h, --help                   Print this message
+";
+
+///
+/// Command line arguments of the renderer.
+///
+#[derive(Debug)]
+pub struct Args {
+    pub settings: RenderSettings,
+    pub headless: bool,
+    pub debug: bool,
+}
+
+impl Args {
+    ///
+    /// Parses the arguments without the program name.
+    /// Returns `None` if the usage was requested.
+    ///
+    pub fn parse(args: impl IntoIterator<Item = String>) -> anyhow::Result<Option<Self>> {
+        let mut args = args.into_iter();
+        let mut options = vec![];
+        let mut scene = None;
+        let mut config = None;
+        let mut headless = false;
+        let mut debug = false;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--headless" => headless = true,
+                "--debug" => debug = true,
+                "-c" | "--config" => config = Some(value(&arg, args.next())?),
+                _ if arg.starts_with('-') => {
+                    let value = value(&arg, args.next())?;
+                    options.push((arg, value));
+                }
+                _ if scene.is_none() => scene = Some(arg),
+                _ => bail!("Unexpected argument {:?}", arg),
+            }
+        }
+
+        // The config file provides the defaults for all other options
+        let mut settings = match config {
+            Some(path) => RenderSettings::from_file(&path)
+                .with_context(|| format!("Failed to read settings from {}", path))?,
+            None => RenderSettings::default(),
+        };
+        if let Some(scene) = scene {
+            settings.scene = scene.into();
+        }
+
+        let mut outputs = vec![];
+        for (option, value) in options {
+            match option.as_str() {
+                "-o" | "--output" => outputs.push(parse_output(&value)?),
+                "--width" => settings.width = parse(&option, &value)?,
+                "--height" => settings.height = parse(&option, &value)?,
+                "--crop" => settings.crop = Some(parse_crop(&value)?),
+                "--sensor-fit" => settings.sensor_fit = Some(parse_sensor_fit(&value)?),
+                "--spp" => settings.spp = parse(&option, &value)?,
+                "--camera" => settings.camera = parse(&option, &value)?,
+                "--integrator" => settings.integrator = value.parse()?,
+                "--seed" => settings.seed = parse(&option, &value)?,
+                "--max-depth" => settings.max_depth = parse(&option, &value)?,
+                "--rr-depth" => settings.rr_depth = parse(&option, &value)?,
+                _ => bail!("Unknown option {}", option),
+            }
+        }
+        if !outputs.is_empty() {
+            settings.outputs = outputs;
+        }
+        for (name, value) in [
+            ("width", settings.width),
+            ("height", settings.height),
+            ("spp", settings.spp),
+        ] {
+            if value == 0 {
+                bail!("Invalid {} 0, expected at least 1", name);
+            }
+        }
+
+        Ok(Some(Self {
+            settings,
+            headless,
+            debug,
+        }))
+    }
+}
+
+fn value(option: &str, value: Option<String>) -> anyhow::Result<String> {
+    value.ok_or_else(|| anyhow!("Missing value for {}", option))
+}
+
+fn parse<T: FromStr>(option: &str, value: &str) -> anyhow::Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid value {:?} for {}", value, option))
+}
+
+///
+/// Parses `X,Y,W,H`.
+///
+fn parse_crop(value: &str) -> anyhow::Result<CropWindow> {
+    let values = value
+        .split(',')
+        .map(|v| parse("--crop", v.trim()))
+        .collect::<anyhow::Result<Vec<u32>>>()?;
+    match values[..] {
+        [x, y, width, height] if width > 0 && height > 0 => Ok(CropWindow {
+            x,
+            y,
+            width,
+            height,
+        }),
+        _ => bail!("Invalid crop window {:?}, expected X,Y,W,H", value),
+    }
+}
+
+fn parse_sensor_fit(value: &str) -> anyhow::Result<SensorFit> {
+    Ok(match value {
+        "horizontal" => SensorFit::Horizontal,
+        "vertical" => SensorFit::Vertical,
+        "fill" => SensorFit::Fill,
+        "overscan" => SensorFit::Overscan,
+        _ => bail!(
+            "Unknown sensor fit {:?}, expected horizontal, vertical, fill or overscan",
+            value
+        ),
+    })
+}
+
+///
+/// Parses `[BUFFER=]FILE`, writing the color buffer if no buffer is given.
+///
+fn parse_output(value: &str) -> anyhow::Result<OutputTarget> {
+    let (buffer, path) = match value.split_once('=') {
+        Some((buffer, path)) => (buffer.parse()?, path),
+        None => (OutputBuffer::Color, value),
+    };
+    Ok(OutputTarget {
+        buffer,
+        path: PathBuf::from(path),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> anyhow::Result<Option<Args>> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn settings(args: &[&str]) -> RenderSettings {
+        parse_args(args).unwrap().unwrap().settings
+    }
+
+    #[test]
+    fn defaults() {
+        let args = parse_args(&[]).unwrap().unwrap();
+        assert_eq!(args.settings, RenderSettings::default());
+        assert!(!args.headless);
+        assert!(!args.debug);
+    }
+
+    #[test]
+    fn flags() {
+        let args = parse_args(&["--debug", "scene.gltf", "--headless"])
+            .unwrap()
+            .unwrap();
+        assert!(args.headless);
+        assert!(args.debug);
+        assert_eq!(args.settings.scene, PathBuf::from("scene.gltf"));
+    }
+
+    #[test]
+    fn help() {
+        assert!(parse_args(&["--spp", "4", "-h"]).unwrap().is_none());
+        assert!(parse_args(&["--help"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn options() {
+        let settings = settings(&[
+            "scene.gltf",
+            "--width",
+            "640",
+            "--height",
+            "480",
+            "--crop",
+            "1, 2,3,4",
+            "--spp",
+            "16",
+            "--camera",
+            "2",
+            "--sensor-fit",
+            "overscan",
+            "--integrator",
+            "megakernel",
+            "--seed",
+            "7",
+            "--max-depth",
+            "5",
+            "--rr-depth",
+            "3",
+        ]);
+        assert_eq!(settings.scene, PathBuf::from("scene.gltf"));
+        assert_eq!(settings.size(), glam::uvec2(640, 480));
+        assert_eq!(
+            settings.crop,
+            Some(CropWindow {
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4,
+            })
+        );
+        assert_eq!(settings.spp, 16);
+        assert_eq!(settings.camera, 2);
+        assert_eq!(settings.sensor_fit, Some(SensorFit::Overscan));
+        assert_eq!(
+            settings.integrator,
+            crate::settings::IntegratorType::Megakernel
+        );
+        assert_eq!(settings.seed, 7);
+        assert_eq!(settings.max_depth, 5);
+        assert_eq!(settings.rr_depth, 3);
+    }
+
+    #[test]
+    fn outputs() {
+        let settings = settings(&[
+            "-o",
+            "color.exr",
+            "--output",
+            "normal=out/normal.exr",
+            "-o",
+            "variance=var.exr",
+        ]);
+        assert_eq!(
+            settings.outputs,
+            vec![
+                OutputTarget {
+                    buffer: OutputBuffer::Color,
+                    path: "color.exr".into(),
+                },
+                OutputTarget {
+                    buffer: OutputBuffer::Normal,
+                    path: "out/normal.exr".into(),
+                },
+                OutputTarget {
+                    buffer: OutputBuffer::Variance,
+                    path: "var.exr".into(),
+                },
+            ]
+        );
+        assert!(parse_args(&["-o", "albedo=albedo.exr"]).is_err());
+    }
+
+    #[test]
+    fn options_override_config() {
+        let path = std::env::temp_dir().join(format!("test-wf-cli-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{
+                "scene": "config.gltf",
+                "width": 320,
+                "spp": 8,
+                "max_depth": 4,
+                "sensor_fit": "fill",
+                "outputs": [{ "buffer": "normal", "path": "normal.exr" }]
+            }"#,
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        // The config is applied first, wherever it appears
+        for args in [
+            &["--spp", "32", "-c", path, "scene.gltf"][..],
+            &["scene.gltf", "--config", path, "--spp", "32"][..],
+        ] {
+            let settings = settings(args);
+            assert_eq!(settings.scene, PathBuf::from("scene.gltf"));
+            assert_eq!(settings.width, 320);
+            assert_eq!(settings.height, RenderSettings::default().height);
+            assert_eq!(settings.spp, 32);
+            assert_eq!(settings.max_depth, 4);
+            assert_eq!(settings.sensor_fit, Some(SensorFit::Fill));
+            assert_eq!(settings.outputs[0].buffer, OutputBuffer::Normal);
+        }
+
+        let settings = settings(&["-c", path, "-o", "img.exr"]);
+        assert_eq!(settings.scene, PathBuf::from("config.gltf"));
+        assert_eq!(
+            settings.outputs,
+            vec![OutputTarget {
+                buffer: OutputBuffer::Color,
+                path: "img.exr".into(),
+            }]
+        );
+
+        std::fs::remove_file(path).unwrap();
+        assert!(parse_args(&["-c", path]).is_err());
+    }
+
+    #[test]
+    fn integrators() {
+        use crate::settings::IntegratorType;
+        for (name, ty) in [
+            ("wavefront", IntegratorType::Wavefront),
+            ("wavefront_ray_query", IntegratorType::WavefrontRayQuery),
+            ("megakernel", IntegratorType::Megakernel),
+            ("reference", IntegratorType::Reference),
+        ] {
+            assert_eq!(settings(&["--integrator", name]).integrator, ty);
+        }
+    }
+
+    #[test]
+    fn missing_values() {
+        for option in ["--spp", "-o", "-c", "--crop", "--sensor-fit"] {
+            let err = parse_args(&["scene.gltf", option]).err().unwrap();
+            assert_eq!(err.to_string(), format!("Missing value for {}", option));
+        }
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert!(parse_args(&["--spp", "many"]).is_err());
+        assert!(parse_args(&["--camera", "-1"]).is_err());
+        assert!(parse_args(&["--crop", "1,2,3"]).is_err());
+        assert!(parse_args(&["--crop", "0,0,0,4"]).is_err());
+        assert!(parse_args(&["--integrator", "bidir"]).is_err());
+        assert!(parse_args(&["--sensor-fit", "stretch"]).is_err());
+    }
+
+    #[test]
+    fn zero_sizes_and_samples() {
+        for option in ["--width", "--height", "--spp"] {
+            let err = parse_args(&[option, "0"]).err().unwrap();
+            assert!(err.to_string().starts_with("Invalid"), "{}", err);
+            assert!(parse_args(&[option, "1"]).is_ok());
+        }
+    }
+
+    #[test]
+    fn unknown_options() {
+        let err = parse_args(&["--samples", "4"]).err().unwrap();
+        assert_eq!(err.to_string(), "Unknown option --samples");
+        assert!(parse_args(&["a.gltf", "b.gltf"]).is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use glam::*;
+use std::collections::HashSet;
+use winit::event::{
+    ElementState, Event, KeyboardInput, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControllerMode {
+    /// Rotates freely around the target, about the axes of the camera.
+    Orbit,
+    /// Moves with WASD, Q and E and looks around with the left mouse button.
+    Fly,
+    /// Rotates around the target, keeping the world up axis upright.
+    Turntable,
+}
+
+impl ControllerMode {
+    pub const ALL: [Self; 3] = [Self::Orbit, Self::Fly, Self::Turntable];
+}
+
+///
+/// Moves a camera from mouse and keyboard input.
+/// Input is collected by `handle_event` and applied once per frame by `update`.
+///
+pub struct CameraController {
+    pub mode: ControllerMode,
+    pub speed: f32,       // Units per second when flying
+    pub sensitivity: f32, // Radians per pixel of mouse movement
+
+    position: Vec3,
+    rotation: Quat,
+    scale: Vec3,
+    target: Vec3, // Center of rotation in orbit and turntable mode
+
+    keys: HashSet<VirtualKeyCode>,
+    rotating: bool,
+    panning: bool,
+    cursor: Option<Vec2>,
+    rotate: Vec2,
+    pan: Vec2,
+    zoom: f32,
+}
+
+impl Default for CameraController {
+    fn default() -> Self {
+        Self {
+            mode: ControllerMode::Orbit,
+            speed: 1.,
+            sensitivity: 0.005,
+            position: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+            target: Vec3::ZERO,
+            keys: HashSet::new(),
+            rotating: false,
+            panning: false,
+            cursor: None,
+            rotate: Vec2::ZERO,
+            pan: Vec2::ZERO,
+            zoom: 0.,
+        }
+    }
+}
+
+impl CameraController {
+    ///
+    /// Takes over the transform of a camera, targeting the point on its view axis closest to the
+    /// origin.
+    ///
+    pub fn reset(&mut self, to_world: Mat4) {
+        let (scale, rotation, position) = to_world.to_scale_rotation_translation();
+        self.position = position;
+        self.rotation = rotation;
+        self.scale = scale;
+        let distance = (-position).dot(self.forward()).max(1.);
+        self.target = position + self.forward() * distance;
+        self.rotate = Vec2::ZERO;
+        self.pan = Vec2::ZERO;
+        self.zoom = 0.;
+    }
+    ///
+    /// Records the input of `event`.
+    /// If `captured` is set, the input is used by the UI and only releases are recorded, so
+    /// that no key or button gets stuck.
+    ///
+    pub fn handle_event(&mut self, event: &Event<()>, captured: bool) {
+        let event = match event {
+            Event::WindowEvent { event, .. } => event,
+            _ => return,
+        };
+        match event {
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        virtual_keycode: Some(key),
+                        state,
+                        ..
+                    },
+                ..
+            } => match state {
+                ElementState::Pressed if !captured => {
+                    self.keys.insert(*key);
+                }
+                ElementState::Released => {
+                    self.keys.remove(key);
+                }
+                _ => {}
+            },
+            WindowEvent::MouseInput { state, button, .. } => {
+                let pressed = *state == ElementState::Pressed;
+                if pressed && captured {
+                    return;
+                }
+                match button {
+                    MouseButton::Left => self.rotating = pressed,
+                    MouseButton::Right | MouseButton::Middle => self.panning = pressed,
+                    _ => {}
+                }
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                let cursor = vec2(position.x as f32, position.y as f32);
+                if let Some(prev) = self.cursor {
+                    let delta = cursor - prev;
+                    if self.rotating {
+                        self.rotate += delta;
+                    }
+                    if self.panning {
+                        self.pan += delta;
+                    }
+                }
+                self.cursor = Some(cursor);
+            }
+            WindowEvent::CursorLeft { .. } => self.cursor = None,
+            WindowEvent::MouseWheel { delta, .. } if !captured => {
+                self.zoom += match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(p) => p.y as f32 / 100.,
+                };
+            }
+            _ => {}
+        }
+    }
+    ///
+    /// Applies the input recorded since the last update.
+    /// Returns the new camera transform if it changed.
+    ///
+    pub fn update(&mut self, dt: f32) -> Option<Mat4> {
+        let rotate = std::mem::take(&mut self.rotate) * self.sensitivity;
+        let pan = std::mem::take(&mut self.pan);
+        let zoom = std::mem::take(&mut self.zoom);
+
+        let (position, rotation) = (self.position, self.rotation);
+        match self.mode {
+            ControllerMode::Orbit => {
+                if rotate != Vec2::ZERO {
+                    let rot = Quat::from_axis_angle(self.up(), -rotate.x)
+                        * Quat::from_axis_angle(self.right(), -rotate.y);
+                    self.position = self.target + rot * (self.position - self.target);
+                    self.rotation = (rot * self.rotation).normalize();
+                }
+                self.pan_and_zoom(pan, zoom);
+            }
+            ControllerMode::Turntable => {
+                if rotate != Vec2::ZERO {
+                    let distance = self.distance();
+                    self.turn(rotate);
+                    self.position = self.target - self.forward() * distance;
+                }
+                self.pan_and_zoom(pan, zoom);
+            }
+            ControllerMode::Fly => {
+                let distance = self.distance();
+                if rotate != Vec2::ZERO {
+                    self.turn(rotate);
+                }
+                if zoom != 0. {
+                    self.speed *= 1.1f32.powf(zoom);
+                }
+
+                let key = |key| self.keys.contains(&key) as i32 as f32;
+                let dir = self.forward() * (key(VirtualKeyCode::W) - key(VirtualKeyCode::S))
+                    + self.right() * (key(VirtualKeyCode::D) - key(VirtualKeyCode::A))
+                    + Vec3::Y * (key(VirtualKeyCode::E) - key(VirtualKeyCode::Q));
+                let fast = self.keys.contains(&VirtualKeyCode::LShift)
+                    || self.keys.contains(&VirtualKeyCode::RShift);
+                let speed = if fast { self.speed * 4. } else { self.speed };
+                if dir != Vec3::ZERO {
+                    self.position += dir.normalize_or_zero() * speed * dt;
+                }
+                self.target = self.position + self.forward() * distance;
+            }
+        }
+
+        if self.position == position && self.rotation == rotation {
+            return None;
+        }
+        Some(Mat4::from_scale_rotation_translation(
+            self.scale,
+            self.rotation,
+            self.position,
+        ))
+    }
+    ///
+    /// Rotates about the world up axis and the right axis of the camera, without tilting over
+    /// the poles.
+    ///
+    fn turn(&mut self, rotate: Vec2) {
+        let yaw = Quat::from_axis_angle(Vec3::Y, -rotate.x);
+        let pitch = Quat::from_axis_angle(self.right(), -rotate.y);
+        let rotation = (yaw * pitch * self.rotation).normalize();
+        self.rotation = if (rotation * Vec3::NEG_Z).y.abs() < 0.99 {
+            rotation
+        } else {
+            (yaw * self.rotation).normalize()
+        };
+    }
+    ///
+    /// Moves the camera and target in the view plane and the camera towards the target.
+    ///
+    fn pan_and_zoom(&mut self, pan: Vec2, zoom: f32) {
+        if pan == Vec2::ZERO && zoom == 0. {
+            return;
+        }
+        let distance = self.distance();
+        let offset = (self.up() * pan.y - self.right() * pan.x) * self.sensitivity * distance * 0.2;
+        self.target += offset;
+        self.position = self.target - self.forward() * distance * 0.9f32.powf(zoom);
+    }
+    fn distance(&self) -> f32 {
+        (self.target - self.position).length()
+    }
+    // The camera looks along its negative z axis
+    fn forward(&self) -> Vec3 {
+        self.rotation * Vec3::NEG_Z
+    }
+    fn right(&self) -> Vec3 {
+        self.rotation * Vec3::X
+    }
+    fn up(&self) -> Vec3 {
+        self.rotation * Vec3::Y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller(mode: ControllerMode) -> CameraController {
+        let mut controller = CameraController {
+            mode,
+            ..Default::default()
+        };
+        let to_world = Mat4::look_at_rh(vec3(1., 2., 5.), vec3(0., 0.5, 0.), Vec3::Y).inverse();
+        controller.reset(to_world);
+        controller
+    }
+
+    fn rotations() -> impl Iterator<Item = Vec2> {
+        (0..100).map(|i| {
+            let i = i as f32;
+            vec2((i * 1.7).sin(), (i * 2.3).cos()) * 40.
+        })
+    }
+
+    #[test]
+    fn no_input_no_update() {
+        for mode in ControllerMode::ALL {
+            let mut controller = controller(mode);
+            assert_eq!(controller.update(0.1), None, "{:?}", mode);
+
+            controller.rotate = vec2(10., 0.);
+            assert!(controller.update(0.1).is_some(), "{:?}", mode);
+            assert_eq!(controller.update(0.1), None, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn rotations_keep_target_distance() {
+        for mode in [ControllerMode::Orbit, ControllerMode::Turntable] {
+            let mut controller = controller(mode);
+            let target = controller.target;
+            let distance = controller.distance();
+            for rotate in rotations() {
+                controller.rotate = rotate;
+                let to_world = controller.update(0.1).unwrap();
+                let position = to_world.w_axis.truncate();
+                assert!(
+                    ((position - target).length() - distance).abs() < 1e-4,
+                    "{:?}",
+                    mode
+                );
+                // The camera keeps looking at the target
+                let forward = -to_world.z_axis.truncate().normalize();
+                assert!(
+                    forward.dot((target - position).normalize()) > 0.9999,
+                    "{:?}",
+                    mode
+                );
+            }
+            assert_eq!(controller.target, target);
+        }
+    }
+
+    #[test]
+    fn turntable_does_not_roll() {
+        let mut controller = controller(ControllerMode::Turntable);
+        for rotate in rotations() {
+            controller.rotate = rotate;
+            let to_world = controller.update(0.1).unwrap();
+            // The right axis stays horizontal and the up axis upright
+            assert!(to_world.x_axis.y.abs() < 1e-5);
+            assert!(to_world.y_axis.y > 0.);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::Context;
+use glam::*;
+use screen_13::prelude::*;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::array::Array;
+
+///
+/// Accumulation target of progressive rendering.
+/// Holds the running sums of all samples and the images of their mean and, optionally, the
+/// variance of the mean.
+///
+pub struct Film {
+    pub size: UVec2,
+    pub spp: u32,
+    pub accum: Array<Vec4>,
+    pub accum_sq: Array<Vec4>,
+    pub image: Arc<Image>,
+    pub variance: Option<Arc<Image>>,
+}
+
+pub fn create_image(device: &Arc<Device>, size: UVec2) -> Arc<Image> {
+    Arc::new(
+        Image::create(
+            device,
+            ImageInfo::new_2d(
+                vk::Format::R32G32B32A32_SFLOAT,
+                size.x,
+                size.y,
+                vk::ImageUsageFlags::STORAGE
+                    | vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::TRANSFER_DST
+                    | vk::ImageUsageFlags::TRANSFER_SRC,
+            ),
+        )
+        .unwrap(),
+    )
+}
+
+impl Film {
+    pub fn new(device: &Arc<Device>, size: UVec2, variance: bool) -> Self {
+        let count = (size.x * size.y) as usize;
+        // The running sums are only accessed on the GPU
+        let usage = vk::BufferUsageFlags::STORAGE_BUFFER
+            | vk::BufferUsageFlags::TRANSFER_DST
+            | vk::BufferUsageFlags::TRANSFER_SRC;
+        Self {
+            size,
+            spp: 0,
+            accum: Array::uninitialized(device, usage, count),
+            accum_sq: Array::uninitialized(device, usage, count),
+            image: create_image(device, size),
+            variance: variance.then(|| create_image(device, size)),
+        }
+    }
+    ///
+    /// Discards all accumulated samples, the next sample overwrites the running sums.
+    ///
+    pub fn reset(&mut self) {
+        self.spp = 0;
+    }
+}
+
+///
+/// Reads back an `R32G32B32A32_SFLOAT` image and writes it to `path`.
+///
+pub fn save_image(
+    device: &Arc<Device>,
+    image: &Arc<Image>,
+    path: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let mut cache = HashPool::new(device);
+    let (width, height) = (image.info.width, image.info.height);
+
+    let img_buf = Array::<[f32; 4]>::empty(device, (width * height) as usize);
+
+    let mut graph = RenderGraph::new();
+    let img_node = graph.bind_node(image);
+    let img_buf_node = graph.bind_node(img_buf.buf());
+    graph.copy_image_to_buffer(img_node, img_buf_node);
+
+    graph.resolve().submit(&mut cache, 0)?;
+    unsafe { device.device_wait_idle()? };
+
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    image::save_buffer(
+        path,
+        img_buf.map_u8(),
+        width,
+        height,
+        image::ColorType::Rgba32F,
+    )
+    .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
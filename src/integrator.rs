@@ -1,42 +1,225 @@
+use common::mis::MisHeuristic;
+use common::sampler::SamplerType;
+use common::workqueue::{DispatchIndirectCommand, IndirectArgs, WORKGROUP_SIZE};
 use common::*;
 use glam::*;
 use screen_13::prelude::*;
+use std::mem::size_of;
 use std::sync::Arc;
 
 use crate::array::Array;
+use crate::film::Film;
 use crate::pipelines::{CPipeline, RTPipeline};
 use crate::scene::{Scene, SceneBinding};
-use crate::workqueue::{ItemWorkQueue, WorkQueue};
+use crate::settings::{CropWindow, OutputBuffer, RenderOutput, RenderSettings};
+use crate::workqueue::WorkQueue;
+
+///
+/// Common interface of the path tracers, so that they can be compared on the same scene.
+///
+pub trait Integrator {
+    ///
+    /// Renders `scene` as described by `settings`. The color buffer holds the mean of all
+    /// samples as an `R32G32B32A32_SFLOAT` image.
+    ///
+    fn render(
+        &mut self,
+        scene: &mut Scene,
+        settings: &RenderSettings,
+    ) -> anyhow::Result<RenderOutput>;
+    ///
+    /// Adds `spp` samples per pixel to the image accumulated since the last `reset` and returns
+    /// it. A different size starts a new image.
+    /// `settings.spp` is ignored, which allows the caller to spread the samples over frames.
+    ///
+    fn accumulate(
+        &mut self,
+        scene: &mut Scene,
+        settings: &RenderSettings,
+        spp: u32,
+    ) -> anyhow::Result<Arc<Image>>;
+    ///
+    /// Discards the accumulated samples.
+    ///
+    fn reset(&mut self);
+}
+
+pub(crate) fn dispatch_size(count: u32) -> u32 {
+    (count + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE
+}
+
+///
+/// Indirect dispatch arguments of a `WorkQueue`, written on the GPU by `write_indirect_args`.
+///
+#[derive(Clone, Copy)]
+pub struct IndirectArgsNode {
+    pub buf: BufferLeaseNode,
+    pub address: vk::DeviceAddress,
+}
+
+impl IndirectArgsNode {
+    pub fn dispatch_offset(&self) -> vk::DeviceSize {
+        0
+    }
+    pub fn trace_rays_address(&self) -> vk::DeviceAddress {
+        self.address + size_of::<DispatchIndirectCommand>() as vk::DeviceAddress
+    }
+}
+
+///
+/// Queues and buffers of a wavefront, kept across samples of the same size.
+///
+struct Wavefront {
+    size: usize,
+    rays: WorkQueue<RayWorkItem>,
+    next_rays: WorkQueue<RayWorkItem>,
+    material_eval_queue: WorkQueue<MaterialEvalWorkItem>,
+    sorted_material_eval_queue: Option<WorkQueue<MaterialEvalWorkItem>>,
+    shadow_rays: WorkQueue<ShadowRayWorkItem>,
+    pixel_states: Array<PixelSampleState>,
+}
+
+impl Wavefront {
+    ///
+    /// Creates the buffers for `size` paths, recording the initialization of the queues into
+    /// `graph`.
+    ///
+    fn new(device: &Arc<Device>, graph: &mut RenderGraph, size: usize, sorted: bool) -> Self {
+        Self {
+            size,
+            rays: WorkQueue::new(device, graph, size),
+            next_rays: WorkQueue::new(device, graph, size),
+            material_eval_queue: WorkQueue::new(device, graph, size),
+            sorted_material_eval_queue: sorted.then(|| WorkQueue::new(device, graph, size)),
+            shadow_rays: WorkQueue::new(device, graph, size),
+            pixel_states: Array::uninitialized(
+                device,
+                vk::BufferUsageFlags::STORAGE_BUFFER
+                    | vk::BufferUsageFlags::TRANSFER_DST
+                    | vk::BufferUsageFlags::TRANSFER_SRC,
+                size,
+            ),
+        }
+    }
+    fn matches(&self, size: usize, sorted: bool) -> bool {
+        self.size == size && self.sorted_material_eval_queue.is_some() == sorted
+    }
+}
+
+///
+/// How `intersect_closest` traces rays against the scene.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntersectionBackend {
+    /// Ray generation shader of a ray tracing pipeline with its shader binding table.
+    RayTracingPipeline,
+    /// Compute shader using ray queries.
+    RayQuery,
+}
+
+enum IntersectClosestPipeline {
+    RayTracing(RTPipeline),
+    RayQuery(CPipeline),
+}
 
 pub struct WavefrontPathIntegrator {
     generate_camera_rays_ppl: CPipeline,
+    sample_bsdf_ppl: CPipeline,
     update_film: CPipeline,
-    intersect_closest_ppl: RTPipeline,
+    film_variance_ppl: CPipeline,
+    write_indirect_args_ppl: CPipeline,
+    count_materials_ppl: CPipeline,
+    scan_material_counts_ppl: CPipeline,
+    scatter_materials_ppl: CPipeline,
+    intersect_closest_ppl: IntersectClosestPipeline,
+    intersect_any_ppl: RTPipeline,
     device: Arc<Device>,
+    pub camera: u32,
+    pub max_depth: u32,
+    pub rr_depth: u32,
+    pub mis: MisHeuristic,
+    pub variance: bool,
+    /// Sort the material evaluation queue by material before shading it, so that neighbouring
+    /// threads evaluate the same material. All materials are still shaded by one dispatch.
+    pub sort_by_material: bool,
+    /// Seed of the random numbers, which only depend on it, the pixel, the sample and the bounce.
+    /// Renders with the same seed and settings are therefore identical, no matter in which order
+    /// the queues hold their items.
+    pub seed: u32,
+    pub sampler: SamplerType,
+    pub sensor_fit: Option<SensorFit>, // Replaces the fit of the camera if set
+    cache: Option<HashPool>,
+    film: Option<Film>,
+    film_region: Option<(UVec2, CropWindow)>, // Film size and crop window `film` renders
+    wavefront: Option<Wavefront>,
 }
 
 impl WavefrontPathIntegrator {
-    pub fn new(device: &Arc<Device>) -> Self {
-        Self {
-            generate_camera_rays_ppl: CPipeline::new(device, "generate_camera_rays"),
-            update_film: CPipeline::new(device, "update_film"),
-            intersect_closest_ppl: RTPipeline::new(device, "intersect_closest", "rchit", "rmiss"),
+    pub fn new(device: &Arc<Device>, backend: IntersectionBackend) -> anyhow::Result<Self> {
+        let intersect_closest_ppl = match backend {
+            IntersectionBackend::RayTracingPipeline => IntersectClosestPipeline::RayTracing(
+                RTPipeline::new(device, "intersect_closest", "rchit", "rmiss")?,
+            ),
+            IntersectionBackend::RayQuery => IntersectClosestPipeline::RayQuery(CPipeline::new(
+                device,
+                "intersect_closest_ray_query",
+            )?),
+        };
+        Ok(Self {
+            generate_camera_rays_ppl: CPipeline::new(device, "generate_camera_rays")?,
+            sample_bsdf_ppl: CPipeline::new(device, "sample_bsdf")?,
+            update_film: CPipeline::new(device, "update_film")?,
+            film_variance_ppl: CPipeline::new(device, "film_variance")?,
+            write_indirect_args_ppl: CPipeline::new(device, "write_indirect_args")?,
+            count_materials_ppl: CPipeline::new(device, "count_materials")?,
+            scan_material_counts_ppl: CPipeline::new(device, "scan_material_counts")?,
+            scatter_materials_ppl: CPipeline::new(device, "scatter_materials")?,
+            intersect_closest_ppl,
+            intersect_any_ppl: RTPipeline::new(device, "intersect_any", "rchit", "rmiss")?,
             device: device.clone(),
-        }
+            camera: 0,
+            max_depth: 8,
+            rr_depth: 2,
+            mis: MisHeuristic::Power,
+            variance: false,
+            sort_by_material: true,
+            seed: 0,
+            sampler: SamplerType::Independent,
+            sensor_fit: None,
+            cache: None,
+            film: None,
+            film_region: None,
+            wavefront: None,
+        })
     }
     pub fn generate_camera_rays(
         &self,
-        scene: SceneBinding,
+        scene: &SceneBinding,
         graph: &mut RenderGraph,
         rays: &WorkQueue<RayWorkItem>,
         pixel_states: &Array<PixelSampleState>,
-        size: UVec2,
+        film_size: UVec2,
+        crop: CropWindow,
+        sample: u32,
     ) {
+        let size = crop.size();
         let rays = graph.bind_node(rays.buf());
         let pixel_states = graph.bind_node(pixel_states.buf());
         // let counter_node = graph.bind_node(rays.counter.buf());
 
-        let pc = GenerateCameraRaysPc { camera: 0 };
+        let pc = GenerateCameraRaysPc {
+            camera: self.camera,
+            width: size.x,
+            height: size.y,
+            film_width: film_size.x,
+            film_height: film_size.y,
+            offset_x: crop.x,
+            offset_y: crop.y,
+            sample,
+            seed: self.seed,
+            sampler: self.sampler as u32,
+            sensor_fit: self.sensor_fit.map_or(SENSOR_FIT_CAMERA, |fit| fit as u32),
+        };
 
         let pass = graph
             .begin_pass("Generate Camera Rays Pass")
@@ -45,130 +228,799 @@ impl WavefrontPathIntegrator {
             .write_descriptor((0, 1), rays)
             .write_descriptor((0, 2), pixel_states)
             .record_compute(move |comp, _| {
-                // comp.push_constants(bytemuck::cast_slice(&[pc]));
-                comp.dispatch(size.x, size.y, 1);
+                comp.push_constants(bytemuck::cast_slice(&[pc]));
+                comp.dispatch(dispatch_size(size.x * size.y), 1, 1);
             });
         pass.submit_pass();
     }
+    ///
+    /// Records a pass that writes the indirect arguments for processing all items of `queue`.
+    ///
+    pub fn write_indirect_args<T: Copy>(
+        &self,
+        cache: &mut HashPool,
+        graph: &mut RenderGraph,
+        queue: &WorkQueue<T>,
+    ) -> anyhow::Result<IndirectArgsNode> {
+        let buf = cache.lease(BufferInfo::new(
+            size_of::<IndirectArgs>() as _,
+            vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::INDIRECT_BUFFER
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                | vk::BufferUsageFlags::TRANSFER_SRC,
+        ))?;
+        let address = Buffer::device_address(&buf);
+        let args = IndirectArgsNode {
+            buf: graph.bind_node(buf),
+            address,
+        };
+        let queue = graph.bind_node(queue.buf());
+
+        graph
+            .begin_pass("Write Indirect Args Pass")
+            .bind_pipeline(self.write_indirect_args_ppl.ppl())
+            .read_descriptor((0, 0), queue)
+            .write_descriptor((0, 1), args.buf)
+            .record_compute(move |comp, _| {
+                comp.dispatch(1, 1, 1);
+            })
+            .submit_pass();
+
+        Ok(args)
+    }
     pub fn intersect_closest(
         &self,
         scene: &SceneBinding,
         graph: &mut RenderGraph,
         rays: &WorkQueue<RayWorkItem>,
+        rays_args: IndirectArgsNode,
         surface_interactions: &WorkQueue<MaterialEvalWorkItem>,
     ) {
-        let size = rays.len();
         let rays = graph.bind_node(rays.buf());
         let material_eval_queue = graph.bind_node(surface_interactions.buf());
 
-        let sbt_rgen = self.intersect_closest_ppl.sbt.rgen();
-        let sbt_miss = self.intersect_closest_ppl.sbt.miss();
-        let sbt_hit = self.intersect_closest_ppl.sbt.hit();
-        let sbt_callable = self.intersect_closest_ppl.sbt.callable();
+        match &self.intersect_closest_ppl {
+            IntersectClosestPipeline::RayTracing(ppl) => {
+                let sbt_rgen = ppl.sbt.rgen();
+                let sbt_miss = ppl.sbt.miss();
+                let sbt_hit = ppl.sbt.hit();
+                let sbt_callable = ppl.sbt.callable();
+
+                graph
+                    .begin_pass("Intersect Closest Pass")
+                    .bind_pipeline(ppl.ppl())
+                    .read_descriptor((0, 0), scene.accel)
+                    .read_descriptor((0, 1), rays)
+                    .write_descriptor((0, 2), material_eval_queue)
+                    .read_descriptor((0, 3), scene.instances)
+                    .read_descriptor((0, 4), scene.meshes)
+                    .read_descriptor((0, 5), scene.indices)
+                    .read_descriptor((0, 6), scene.normals)
+                    .read_descriptor((0, 7), scene.uvs)
+                    .access_node(rays_args.buf, AccessType::IndirectBuffer)
+                    .record_ray_trace(move |rt, _| {
+                        rt.trace_rays_indirect(
+                            &sbt_rgen,
+                            &sbt_miss,
+                            &sbt_hit,
+                            &sbt_callable,
+                            rays_args.trace_rays_address(),
+                        );
+                    })
+                    .submit_pass();
+            }
+            IntersectClosestPipeline::RayQuery(ppl) => {
+                graph
+                    .begin_pass("Intersect Closest Ray Query Pass")
+                    .bind_pipeline(ppl.ppl())
+                    .read_descriptor((0, 0), scene.accel)
+                    .read_descriptor((0, 1), rays)
+                    .write_descriptor((0, 2), material_eval_queue)
+                    .read_descriptor((0, 3), scene.instances)
+                    .read_descriptor((0, 4), scene.meshes)
+                    .read_descriptor((0, 5), scene.indices)
+                    .read_descriptor((0, 6), scene.normals)
+                    .read_descriptor((0, 7), scene.uvs)
+                    .access_node(rays_args.buf, AccessType::IndirectBuffer)
+                    .record_compute(move |comp, _| {
+                        comp.dispatch_indirect(rays_args.buf, rays_args.dispatch_offset());
+                    })
+                    .submit_pass();
+            }
+        }
+    }
+    pub fn intersect_any(
+        &self,
+        scene: &SceneBinding,
+        graph: &mut RenderGraph,
+        shadow_rays: &WorkQueue<ShadowRayWorkItem>,
+        shadow_rays_args: IndirectArgsNode,
+        pixel_states: &Array<PixelSampleState>,
+    ) {
+        let shadow_rays = graph.bind_node(shadow_rays.buf());
+        let pixel_states = graph.bind_node(pixel_states.buf());
+
+        let sbt_rgen = self.intersect_any_ppl.sbt.rgen();
+        let sbt_miss = self.intersect_any_ppl.sbt.miss();
+        let sbt_hit = self.intersect_any_ppl.sbt.hit();
+        let sbt_callable = self.intersect_any_ppl.sbt.callable();
 
         let pass = graph
-            .begin_pass("Intersect Closest Pass")
-            .bind_pipeline(self.intersect_closest_ppl.ppl())
+            .begin_pass("Intersect Any Pass")
+            .bind_pipeline(self.intersect_any_ppl.ppl())
             .read_descriptor((0, 0), scene.accel)
-            .read_descriptor((0, 1), rays)
-            .write_descriptor((0, 2), material_eval_queue)
+            .read_descriptor((0, 1), shadow_rays)
+            .write_descriptor((0, 2), pixel_states)
+            .access_node(shadow_rays_args.buf, AccessType::IndirectBuffer)
             .record_ray_trace(move |rt, _| {
-                rt.trace_rays(
+                rt.trace_rays_indirect(
                     &sbt_rgen,
                     &sbt_miss,
                     &sbt_hit,
                     &sbt_callable,
-                    size as _,
-                    1,
-                    1,
+                    shadow_rays_args.trace_rays_address(),
                 );
             });
         pass.submit_pass();
     }
+    pub fn sample_bsdf(
+        &self,
+        scene: &SceneBinding,
+        graph: &mut RenderGraph,
+        material_eval_queue: &WorkQueue<MaterialEvalWorkItem>,
+        material_eval_args: IndirectArgsNode,
+        next_rays: &WorkQueue<RayWorkItem>,
+        shadow_rays: &WorkQueue<ShadowRayWorkItem>,
+        pixel_states: &Array<PixelSampleState>,
+    ) {
+        let material_eval_queue = graph.bind_node(material_eval_queue.buf());
+        let next_rays = graph.bind_node(next_rays.buf());
+        let shadow_rays = graph.bind_node(shadow_rays.buf());
+        let pixel_states = graph.bind_node(pixel_states.buf());
+
+        let mut pass = graph
+            .begin_pass("Sample BSDF Pass")
+            .bind_pipeline(self.sample_bsdf_ppl.ppl())
+            .read_descriptor((0, 0), material_eval_queue)
+            .write_descriptor((0, 1), next_rays)
+            .write_descriptor((0, 2), pixel_states)
+            .read_descriptor((0, 3), scene.instances)
+            .read_descriptor((0, 4), scene.materials)
+            .read_descriptor((0, 5), scene.emitters);
+        for (i, texture) in scene.textures.iter().enumerate() {
+            pass = pass.read_descriptor((0, 6, [i as _]), *texture);
+        }
+        let pass = pass
+            .write_descriptor((0, 7), shadow_rays)
+            .read_descriptor((0, 8), scene.meshes)
+            .read_descriptor((0, 9), scene.indices)
+            .read_descriptor((0, 10), scene.positions)
+            .read_descriptor((0, 11), scene.emitter_cdfs)
+            .read_descriptor((0, 12), scene.uvs)
+            .access_node(material_eval_args.buf, AccessType::IndirectBuffer);
+
+        let pc = SampleBsdfPc {
+            mis: self.mis as u32,
+            max_depth: self.max_depth,
+            rr_depth: self.rr_depth,
+            sampler: self.sampler as u32,
+        };
+
+        let pass = pass.record_compute(move |comp, _| {
+            comp.push_constants(bytemuck::cast_slice(&[pc]));
+            comp.dispatch_indirect(material_eval_args.buf, material_eval_args.dispatch_offset());
+        });
+        pass.submit_pass();
+    }
+    ///
+    /// Records a counting sort of `material_eval_queue` by material into `sorted`, so that items
+    /// with the same material are shaded by neighbouring threads.
+    ///
+    pub fn sort_materials(
+        &self,
+        scene: &SceneBinding,
+        cache: &mut HashPool,
+        graph: &mut RenderGraph,
+        material_eval_queue: &WorkQueue<MaterialEvalWorkItem>,
+        material_eval_args: IndirectArgsNode,
+        sorted: &WorkQueue<MaterialEvalWorkItem>,
+    ) -> anyhow::Result<()> {
+        let material_count = scene.material_count.max(1);
+        let counts = cache.lease(BufferInfo::new(
+            (size_of::<u32>() * material_count as usize) as _,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+        ))?;
+        let counts = graph.bind_node(counts);
+        graph.fill_buffer(counts, 0);
+
+        // Items of an empty queue are not scattered, so the length has to be reset beforehand.
+        sorted.reset(graph);
+
+        let material_eval_queue = graph.bind_node(material_eval_queue.buf());
+        let sorted = graph.bind_node(sorted.buf());
+
+        graph
+            .begin_pass("Count Materials Pass")
+            .bind_pipeline(self.count_materials_ppl.ppl())
+            .read_descriptor((0, 0), material_eval_queue)
+            .write_descriptor((0, 1), counts)
+            .access_node(material_eval_args.buf, AccessType::IndirectBuffer)
+            .record_compute(move |comp, _| {
+                comp.dispatch_indirect(
+                    material_eval_args.buf,
+                    material_eval_args.dispatch_offset(),
+                );
+            })
+            .submit_pass();
+
+        let pc = SortMaterialsPc { material_count };
+
+        graph
+            .begin_pass("Scan Material Counts Pass")
+            .bind_pipeline(self.scan_material_counts_ppl.ppl())
+            .write_descriptor((0, 0), counts)
+            .record_compute(move |comp, _| {
+                comp.push_constants(bytemuck::cast_slice(&[pc]));
+                comp.dispatch(1, 1, 1);
+            })
+            .submit_pass();
+
+        graph
+            .begin_pass("Scatter Materials Pass")
+            .bind_pipeline(self.scatter_materials_ppl.ppl())
+            .read_descriptor((0, 0), material_eval_queue)
+            .write_descriptor((0, 1), counts)
+            .write_descriptor((0, 2), sorted)
+            .access_node(material_eval_args.buf, AccessType::IndirectBuffer)
+            .record_compute(move |comp, _| {
+                comp.dispatch_indirect(
+                    material_eval_args.buf,
+                    material_eval_args.dispatch_offset(),
+                );
+            })
+            .submit_pass();
+        Ok(())
+    }
     pub fn update_film(
         &self,
         graph: &mut RenderGraph,
         pixel_states: &Array<PixelSampleState>,
-        image: &Arc<Image>,
-        size: UVec2,
+        film: &Film,
     ) {
+        let size = film.size;
         let pixel_states = graph.bind_node(pixel_states.buf());
-        let image = graph.bind_node(image);
+        let image = graph.bind_node(&film.image);
+        let accum = graph.bind_node(film.accum.buf());
+        let accum_sq = graph.bind_node(film.accum_sq.buf());
 
-        let pass = graph
-            .begin_pass("Generate Camera Rays Pass")
+        let pc = UpdateFilmPc {
+            sample: film.spp,
+            width: size.x,
+        };
+
+        graph
+            .begin_pass("Update Film Pass")
             .bind_pipeline(self.update_film.ppl())
             .read_descriptor((0, 0), pixel_states)
             .write_descriptor((0, 1), image)
+            .write_descriptor((0, 2), accum)
+            .write_descriptor((0, 3), accum_sq)
             .record_compute(move |comp, _| {
-                // comp.push_constants(bytemuck::cast_slice(&[pc]));
-                comp.dispatch(size.x, size.y, 1);
-            });
-        pass.submit_pass();
+                comp.push_constants(bytemuck::cast_slice(&[pc]));
+                comp.dispatch(dispatch_size(size.x * size.y), 1, 1);
+            })
+            .submit_pass();
+
+        if let Some(variance) = &film.variance {
+            let variance = graph.bind_node(variance);
+            let pc = UpdateFilmPc {
+                sample: film.spp + 1,
+                width: size.x,
+            };
+
+            graph
+                .begin_pass("Film Variance Pass")
+                .bind_pipeline(self.film_variance_ppl.ppl())
+                .read_descriptor((0, 0), accum)
+                .read_descriptor((0, 1), accum_sq)
+                .write_descriptor((0, 2), variance)
+                .record_compute(move |comp, _| {
+                    comp.push_constants(bytemuck::cast_slice(&[pc]));
+                    comp.dispatch(dispatch_size(size.x * size.y), 1, 1);
+                })
+                .submit_pass();
+        }
     }
-    pub fn render(&self, scene: &mut Scene, size: UVec2) {
-        let mut graph = RenderGraph::new();
-        let mut cache = HashPool::new(&self.device);
+    ///
+    /// Records tracing one sample per pixel and adding it to the film into `graph`.
+    ///
+    fn record_sample(
+        &self,
+        scene: &SceneBinding,
+        film: &Film,
+        wavefront: &Wavefront,
+        film_size: UVec2,
+        crop: CropWindow,
+        cache: &mut HashPool,
+        graph: &mut RenderGraph,
+    ) -> anyhow::Result<()> {
+        let sample = film.spp;
+        let Wavefront {
+            rays,
+            next_rays,
+            material_eval_queue,
+            sorted_material_eval_queue,
+            shadow_rays,
+            pixel_states,
+            ..
+        } = wavefront;
+        let (mut rays, mut next_rays) = (rays, next_rays);
 
-        scene.update(&self.device, &mut cache, &mut graph);
+        rays.reset(graph);
+        self.generate_camera_rays(scene, graph, rays, pixel_states, film_size, crop, sample);
 
-        let wavefront_size = (size.x * size.y) as usize;
+        // Empty queues result in empty dispatches, so the loop does not need to read back the
+        // queue lengths.
+        for _ in 0..self.max_depth {
+            next_rays.reset(graph);
+            material_eval_queue.reset(graph);
+            shadow_rays.reset(graph);
 
-        let scene_bindings = scene.bind(&mut graph);
-        let current = WorkQueue::new(&self.device, wavefront_size);
-        // let next = WorkQueue::new(&self.device, wavefront_size);
-        let pixel_states = Array::empty(&self.device, wavefront_size);
-        let material_eval_queue = WorkQueue::new(&self.device, wavefront_size);
-        let img = Image::create(
-            &self.device,
-            ImageInfo::new_2d(
-                vk::Format::R32G32B32A32_SFLOAT,
-                size.x,
-                size.y,
-                vk::ImageUsageFlags::STORAGE
-                    | vk::ImageUsageFlags::TRANSFER_DST
-                    | vk::ImageUsageFlags::TRANSFER_SRC,
-            ),
-        )
-        .unwrap();
-        let img = Arc::new(img);
-        let img_buf = Array::<[f32; 4]>::empty(&self.device, wavefront_size);
+            let rays_args = self.write_indirect_args(cache, graph, rays)?;
+            self.intersect_closest(scene, graph, rays, rays_args, material_eval_queue);
 
-        self.generate_camera_rays(scene_bindings, &mut graph, &current, &pixel_states, size);
+            let material_eval_args = self.write_indirect_args(cache, graph, material_eval_queue)?;
+            let shading_queue = match sorted_material_eval_queue {
+                Some(sorted) => {
+                    self.sort_materials(
+                        scene,
+                        cache,
+                        graph,
+                        material_eval_queue,
+                        material_eval_args,
+                        sorted,
+                    )?;
+                    sorted
+                }
+                None => material_eval_queue,
+            };
+            self.sample_bsdf(
+                scene,
+                graph,
+                shading_queue,
+                material_eval_args,
+                next_rays,
+                shadow_rays,
+                pixel_states,
+            );
 
-        graph.resolve().submit(&mut cache, 0).unwrap();
-        unsafe { self.device.device_wait_idle().unwrap() };
-        let mut graph = RenderGraph::new();
-        let scene_bindings = scene.bind(&mut graph);
+            let shadow_rays_args = self.write_indirect_args(cache, graph, shadow_rays)?;
+            self.intersect_any(scene, graph, shadow_rays, shadow_rays_args, pixel_states);
 
-        self.intersect_closest(&scene_bindings, &mut graph, &current, &material_eval_queue);
+            std::mem::swap(&mut rays, &mut next_rays);
+        }
 
-        graph.resolve().submit(&mut cache, 0).unwrap();
-        unsafe { self.device.device_wait_idle().unwrap() };
-        let mut graph = RenderGraph::new();
-        let scene_bindings = scene.bind(&mut graph);
+        self.update_film(graph, pixel_states, film);
+        Ok(())
+    }
+    ///
+    /// Accumulates `spp` samples per pixel of the region `crop` of a film of `size` into the
+    /// persistent film, which has the size of the region.
+    /// Repeated calls with the same region refine the same image, a different one starts a new
+    /// one.
+    /// All samples are recorded into a single render graph, which also builds the scene if it
+    /// has not been built yet.
+    ///
+    pub fn render_progressive(
+        &mut self,
+        scene: &mut Scene,
+        size: UVec2,
+        crop: CropWindow,
+        spp: u32,
+    ) -> anyhow::Result<&Film> {
+        let mut cache = self
+            .cache
+            .take()
+            .unwrap_or_else(|| HashPool::new(&self.device));
 
-        self.update_film(&mut graph, &pixel_states, &img, size);
+        let region = Some((size, crop));
+        let mut film = match self.film.take() {
+            Some(film)
+                if self.film_region == region && film.variance.is_some() == self.variance =>
+            {
+                film
+            }
+            _ => Film::new(&self.device, crop.size(), self.variance),
+        };
+        self.film_region = region;
 
-        let img_node = graph.bind_node(img);
-        let img_buf_node = graph.bind_node(img_buf.buf());
-        graph.copy_image_to_buffer(img_node, img_buf_node);
+        let wavefront_size = (crop.width * crop.height) as usize;
+        let sorted = self.sort_by_material;
 
-        graph.resolve().submit(&mut cache, 0).unwrap();
-        unsafe { self.device.device_wait_idle().unwrap() };
         let mut graph = RenderGraph::new();
+
+        if scene.tlas.is_none() {
+            scene.update(&self.device, &mut cache, &mut graph);
+        }
         let scene_bindings = scene.bind(&mut graph);
 
-        image::save_buffer(
-            "out/img.exr",
-            img_buf.map_u8(),
-            size.x,
-            size.y,
-            image::ColorType::Rgba32F,
-        )
-        .unwrap();
-
-        dbg!(material_eval_queue.items());
-        dbg!(material_eval_queue.len());
+        let wavefront = match self.wavefront.take() {
+            Some(wavefront) if wavefront.matches(wavefront_size, sorted) => wavefront,
+            _ => Wavefront::new(&self.device, &mut graph, wavefront_size, sorted),
+        };
+        for _ in 0..spp {
+            self.record_sample(
+                &scene_bindings,
+                &film,
+                &wavefront,
+                size,
+                crop,
+                &mut cache,
+                &mut graph,
+            )?;
+            film.spp += 1;
+        }
+        self.wavefront = Some(wavefront);
+
+        let mut cmd_buf = graph.resolve().submit(&mut cache, 0)?;
+        cmd_buf.wait_until_executed()?;
+        self.cache = Some(cache);
+
+        Ok(self.film.insert(film))
+    }
+    ///
+    /// Takes over the parameters of `settings` that are shared by all integrators.
+    ///
+    fn apply(&mut self, settings: &RenderSettings) {
+        self.camera = settings.camera;
+        self.max_depth = settings.max_depth;
+        self.rr_depth = settings.rr_depth;
+        self.seed = settings.seed;
+        self.mis = settings.mis;
+        self.sampler = settings.sampler;
+        self.sort_by_material = settings.sort_by_material;
+        self.sensor_fit = settings.sensor_fit;
+    }
+}
+
+impl Integrator for WavefrontPathIntegrator {
+    fn render(
+        &mut self,
+        scene: &mut Scene,
+        settings: &RenderSettings,
+    ) -> anyhow::Result<RenderOutput> {
+        self.apply(settings);
+        self.variance = settings.wants(OutputBuffer::Variance);
+
+        self.reset();
+        let film =
+            self.render_progressive(scene, settings.size(), settings.crop_window(), settings.spp)?;
+        Ok(RenderOutput {
+            color: film.image.clone(),
+            variance: film.variance.clone(),
+            normal: None,
+            position: None,
+        })
+    }
+    fn accumulate(
+        &mut self,
+        scene: &mut Scene,
+        settings: &RenderSettings,
+        spp: u32,
+    ) -> anyhow::Result<Arc<Image>> {
+        self.apply(settings);
+        let film = self.render_progressive(scene, settings.size(), settings.crop_window(), spp)?;
+        Ok(film.image.clone())
+    }
+    fn reset(&mut self) {
+        if let Some(film) = &mut self.film {
+            film.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_PI_2;
+
+    const RADIANCE: Vec3 = vec3(1., 2., 4.);
+    const SIZE: UVec2 = uvec2(32, 32);
+
+    struct Context {
+        device: Arc<Device>,
+        integrator: WavefrontPathIntegrator,
+    }
+
+    impl Context {
+        fn new() -> Self {
+            let device = Arc::new(
+                Device::create_headless(DriverConfig::new().ray_tracing(true).build()).unwrap(),
+            );
+            let integrator =
+                WavefrontPathIntegrator::new(&device, IntersectionBackend::RayTracingPipeline)
+                    .unwrap();
+            Self { device, integrator }
+        }
+        fn submit(&self, graph: RenderGraph) {
+            let mut cache = HashPool::new(&self.device);
+            graph.resolve().submit(&mut cache, 0).unwrap();
+            unsafe { self.device.device_wait_idle().unwrap() };
+        }
+        fn read_image(&self, image: &Arc<Image>) -> Vec<Vec4> {
+            let pixels =
+                Array::<Vec4>::empty(&self.device, (image.info.width * image.info.height) as _);
+
+            let mut graph = RenderGraph::new();
+            let image = graph.bind_node(image);
+            let buf = graph.bind_node(pixels.buf());
+            graph.copy_image_to_buffer(image, buf);
+            self.submit(graph);
+
+            pixels.map().to_vec()
+        }
+        ///
+        /// Adds `spp` samples per pixel to the film and returns its pixels.
+        ///
+        fn render(&mut self, scene: &mut Scene, spp: u32) -> Vec<Vec4> {
+            let film = self
+                .integrator
+                .render_progressive(scene, SIZE, CropWindow::full(SIZE), spp)
+                .unwrap();
+            let image = film.image.clone();
+            self.read_image(&image)
+        }
+    }
+
+    fn material(base_color: f32) -> Material {
+        // An index of refraction of 1 turns off the specular lobe, which leaves a Lambertian
+        // surface
+        Material {
+            base_color: Texture::constant(Vec3::splat(base_color)),
+            metallic_roughness: Texture::constant(vec3(0., 1., 0.)),
+            transmission: Texture::constant(Vec3::ZERO),
+            ior: 1.,
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// Furnace: a closed cube around the camera, whose Lambertian walls of albedo `albedo` face
+    /// inwards and all emit `RADIANCE`.
+    ///
+    fn furnace(albedo: f32) -> Scene {
+        let mut scene = Scene {
+            indices: vec![0, 1, 2, 0, 2, 3],
+            positions: vec![
+                vec3(-1., -1., 0.),
+                vec3(1., -1., 0.),
+                vec3(1., 1., 0.),
+                vec3(-1., 1., 0.),
+            ],
+            normals: vec![Vec3::Z; 4],
+            uvs: vec![Vec2::ZERO; 4],
+            meshes: vec![Mesh {
+                indices: 0,
+                indices_count: 6,
+                positions: 0,
+                normals: 0,
+                uvs: 0,
+            }],
+            materials: vec![material(albedo)],
+            cameras: vec![Camera::perspective(
+                Mat4::IDENTITY,
+                FRAC_PI_2,
+                1.,
+                SensorFit::Vertical,
+                0.001,
+                100.,
+            )],
+            ..Default::default()
+        };
+        for n in [
+            Vec3::X,
+            Vec3::NEG_X,
+            Vec3::Y,
+            Vec3::NEG_Y,
+            Vec3::Z,
+            Vec3::NEG_Z,
+        ] {
+            let instance = scene.instances.len() as u32;
+            scene.instances.push(Instance {
+                to_world: Mat4::from_rotation_translation(Quat::from_rotation_arc(Vec3::Z, n), -n),
+                mesh: 0,
+                material: 0,
+                emitter: scene.emitters.len() as i32,
+            });
+            scene
+                .emitters
+                .push(Emitter::area(Texture::constant(RADIANCE), instance));
+        }
+        scene
+    }
+
+    ///
+    /// Radiance every pixel of the furnace converges to, the emission reflected up to
+    /// `max_depth` times.
+    ///
+    fn furnace_radiance(albedo: f32, max_depth: u32) -> Vec3 {
+        RADIANCE * (0..=max_depth).map(|k| albedo.powi(k as i32)).sum::<f32>()
+    }
+
+    fn mean(pixels: &[Vec4]) -> Vec3 {
+        let sum = pixels.iter().fold(Vec3::ZERO, |sum, p| sum + p.xyz());
+        sum / pixels.len() as f32
+    }
+
+    fn assert_close(value: Vec3, expected: Vec3, tolerance: f32) {
+        assert!(
+            ((value - expected).abs() / expected).max_element() <= tolerance,
+            "{value} != {expected}"
+        );
+    }
+
+    #[test]
+    fn paths_bounce_up_to_the_max_depth() {
+        let mut ctx = Context::new();
+        for max_depth in [1, 2, 4] {
+            let mut scene = furnace(0.5);
+            ctx.integrator.max_depth = max_depth;
+            ctx.integrator.rr_depth = max_depth;
+            ctx.integrator.reset();
+
+            let pixels = ctx.render(&mut scene, 64);
+            assert_close(mean(&pixels), furnace_radiance(0.5, max_depth), 0.02);
+        }
+    }
+
+    #[test]
+    fn samples_accumulate_across_calls() {
+        let mut ctx = Context::new();
+        let mut scene = furnace(0.5);
+        ctx.integrator.max_depth = 2;
+
+        let first = ctx.render(&mut scene, 4);
+        let second = ctx.render(&mut scene, 4);
+        assert_eq!(ctx.integrator.film.as_ref().unwrap().spp, 8);
+        assert_ne!(first, second);
+
+        // The running mean of both calls is the mean of all their samples
+        ctx.integrator.reset();
+        let all = ctx.render(&mut scene, 8);
+        for (a, b) in second.iter().zip(&all) {
+            assert!(a.abs_diff_eq(*b, 1e-5), "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn variance_of_the_mean() {
+        let mut ctx = Context::new();
+        ctx.integrator.variance = true;
+        ctx.integrator.max_depth = 2;
+
+        // Every sample of a black furnace sees the emission of the walls
+        let mut scene = furnace(0.);
+        for _ in 0..2 {
+            let pixels = ctx.render(&mut scene, 2);
+            assert!(pixels.iter().all(|p| p.xyz() == RADIANCE));
+            let film = ctx.integrator.film.as_ref().unwrap();
+            let variance = ctx.read_image(&film.variance.clone().unwrap());
+            assert!(variance.iter().all(|v| v.xyz() == Vec3::ZERO));
+        }
+
+        let mut scene = furnace(0.5);
+        ctx.integrator.reset();
+        ctx.render(&mut scene, 4);
+        let film = ctx.integrator.film.as_ref().unwrap();
+        let variance = ctx.read_image(&film.variance.clone().unwrap());
+        assert!(mean(&variance).min_element() > 0.);
+    }
+
+    #[test]
+    fn russian_roulette_is_unbiased() {
+        let mut ctx = Context::new();
+        for albedo in [0.5, 0.8] {
+            let mut scene = furnace(albedo);
+            ctx.integrator.max_depth = 6;
+            ctx.integrator.rr_depth = 0;
+            ctx.integrator.reset();
+
+            let pixels = ctx.render(&mut scene, 64);
+            assert_close(mean(&pixels), furnace_radiance(albedo, 6), 0.02);
+        }
+    }
+
+    #[test]
+    fn russian_roulette_shrinks_the_wavefront() {
+        let mut ctx = Context::new();
+        let pixels = (SIZE.x * SIZE.y) as usize;
+        ctx.integrator.max_depth = 3;
+
+        // With three bounces the rays of the second bounce are left in `rays`. Paths in the
+        // closed furnace only end by Russian roulette, which keeps about a quarter of them.
+        for (rr_depth, expected) in [
+            (3, pixels - pixels / 16..pixels + 1),
+            (0, pixels / 8..pixels / 2),
+        ] {
+            let mut scene = furnace(0.5);
+            ctx.integrator.rr_depth = rr_depth;
+            ctx.integrator.reset();
+            ctx.render(&mut scene, 1);
+
+            let mut graph = RenderGraph::new();
+            let wavefront = ctx.integrator.wavefront.as_ref().unwrap();
+            let rays = wavefront.rays.readback(&ctx.device, &mut graph);
+            ctx.submit(graph);
+            assert!(expected.contains(&rays.len()), "{} rays", rays.len());
+        }
+    }
+
+    #[test]
+    fn indirect_args_cover_the_queue() {
+        let ctx = Context::new();
+        let mut cache = HashPool::new(&ctx.device);
+
+        for len in [0, 1, 63, 64, 65, 1000] {
+            let mut graph = RenderGraph::new();
+            let queue = WorkQueue::<u32>::new(&ctx.device, &mut graph, 1000);
+            let queue_node = graph.bind_node(queue.buf());
+            graph.fill_buffer_region(queue_node, len as u32, 4..8);
+
+            let args = ctx
+                .integrator
+                .write_indirect_args(&mut cache, &mut graph, &queue)
+                .unwrap();
+            let host = Array::<IndirectArgs>::empty(&ctx.device, 1);
+            let host_node = graph.bind_node(host.buf());
+            graph.copy_buffer(args.buf, host_node);
+            ctx.submit(graph);
+
+            let args = host.map()[0];
+            assert_eq!(
+                [args.dispatch.x, args.dispatch.y, args.dispatch.z],
+                [dispatch_size(len as u32), 1, 1]
+            );
+            assert_eq!(
+                [
+                    args.trace_rays.width,
+                    args.trace_rays.height,
+                    args.trace_rays.depth
+                ],
+                [len as u32, 1, 1]
+            );
+        }
+    }
+
+    #[test]
+    fn render_builds_the_scene_in_its_graph() {
+        let mut ctx = Context::new();
+        let mut scene = furnace(0.);
+        ctx.integrator.max_depth = 2;
+
+        for _ in 0..2 {
+            ctx.integrator.reset();
+            let pixels = ctx.render(&mut scene, 4);
+            assert!(scene.tlas.is_some());
+            assert!(ctx.integrator.cache.is_some());
+            assert_eq!(ctx.integrator.film.as_ref().unwrap().spp, 4);
+            assert!(pixels.iter().all(|p| p.xyz() == RADIANCE));
+        }
+    }
+
+    #[test]
+    fn renders_with_the_same_seed_are_identical() {
+        let mut ctx = Context::new();
+        let mut scene = furnace(0.5);
+        ctx.integrator.max_depth = 4;
+        ctx.integrator.rr_depth = 0;
+
+        let mut render = |seed| {
+            ctx.integrator.seed = seed;
+            ctx.integrator.reset();
+            ctx.render(&mut scene, 4)
+        };
+        let first = render(1);
+        assert_eq!(render(1), first);
+        assert_ne!(render(2), first);
     }
 }
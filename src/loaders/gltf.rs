@@ -1,6 +1,9 @@
+use anyhow::{anyhow, bail, Context};
 use common::*;
 use glam::*;
+use gltf::camera::Projection;
 use screen_13_fx::ImageLoader;
+use serde::Deserialize;
 use std::path::Path;
 
 use crate::scene::Scene;
@@ -10,25 +13,93 @@ use super::Loader;
 #[derive(Default)]
 pub struct GltfLoader {}
 
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum PanoramicProjection {
+    Equirectangular,
+    Fisheye, // With the vertical field of view of the glTF camera across the image circle
+}
+
+///
+/// Camera parameters glTF has no notion of, read from the extras of the glTF camera and, as
+/// Blender exports custom properties of objects, of its node.
+/// Where both set a parameter the one of the camera is taken.
+///
+#[derive(Deserialize)]
+#[serde(default)]
+struct CameraExtras {
+    projection: Option<PanoramicProjection>, // Replaces a perspective projection
+    aperture_radius: f32,
+    focus_distance: f32,
+    aperture_blades: u32,
+    aperture_rotation: f32, // In degrees
+}
+
+impl Default for CameraExtras {
+    fn default() -> Self {
+        Self {
+            projection: None,
+            aperture_radius: 0.,
+            focus_distance: 1.,
+            aperture_blades: 0,
+            aperture_rotation: 0.,
+        }
+    }
+}
+
+impl CameraExtras {
+    fn read(camera: &gltf::Camera, node: &gltf::Node) -> anyhow::Result<Self> {
+        let invalid = || {
+            format!(
+                "Invalid camera parameters in the extras of camera {}",
+                camera.index()
+            )
+        };
+
+        let mut params = serde_json::Map::new();
+        for extras in [node.extras(), camera.extras()].into_iter().flatten() {
+            let extras: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_str(extras.get()).with_context(invalid)?;
+            params.extend(extras);
+        }
+        let extras: Self = serde_json::from_value(params.into()).with_context(invalid)?;
+
+        if extras.focus_distance <= 0. {
+            bail!(
+                "Camera {} has the focus distance {}, it must be positive",
+                camera.index(),
+                extras.focus_distance
+            );
+        }
+        if extras.aperture_radius < 0. {
+            bail!(
+                "Camera {} has the aperture radius {}, it must not be negative",
+                camera.index(),
+                extras.aperture_radius
+            );
+        }
+        Ok(extras)
+    }
+}
+
 impl Loader<Scene> for GltfLoader {
-    fn append(&self, path: impl AsRef<Path>, dst: &mut Scene) -> usize {
+    fn append(&self, path: impl AsRef<Path>, dst: &mut Scene) -> anyhow::Result<usize> {
         let path = path.as_ref();
-        let (gltf, buffers, _) = gltf::import(path).unwrap();
+        let (gltf, buffers, _) =
+            gltf::import(path).with_context(|| format!("Failed to import {}", path.display()))?;
 
         let texture_offset = dst.textures.len();
         for texture in gltf.textures() {
             let img = match texture.source().source() {
                 gltf::image::Source::Uri { uri, mime_type } => {
-                    let parent = Path::new(path).parent().unwrap();
+                    let parent = path.parent().unwrap_or(Path::new(""));
                     let img_path = parent.join(uri);
-                    let img = image::io::Reader::open(img_path)
-                        .unwrap()
-                        .decode()
-                        .unwrap()
+                    let img = image::open(&img_path)
+                        .with_context(|| format!("Failed to load {}", img_path.display()))?
                         .into_rgba8();
                     image::DynamicImage::ImageRgba8(img)
                 }
-                _ => unimplemented!(),
+                _ => bail!("Embedded images are not supported"),
             };
             dst.textures.push(img);
         }
@@ -40,19 +111,34 @@ impl Loader<Scene> for GltfLoader {
             let normals_offset = dst.normals.len();
             let uvs_offset = dst.uvs.len();
 
-            let primitive = mesh.primitives().next().unwrap();
+            let missing = |attribute| anyhow!("Mesh {} has no {}", mesh.index(), attribute);
+            let primitive = mesh
+                .primitives()
+                .next()
+                .ok_or_else(|| missing("primitives"))?;
             let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
 
-            for pos in reader.read_positions().unwrap() {
+            for pos in reader
+                .read_positions()
+                .ok_or_else(|| missing("positions"))?
+            {
                 dst.positions.push(vec3(pos[0], pos[1], pos[2]));
             }
-            for index in reader.read_indices().unwrap().into_u32() {
+            for index in reader
+                .read_indices()
+                .ok_or_else(|| missing("indices"))?
+                .into_u32()
+            {
                 dst.indices.push(index);
             }
-            for normal in reader.read_normals().unwrap() {
+            for normal in reader.read_normals().ok_or_else(|| missing("normals"))? {
                 dst.normals.push(vec3(normal[0], normal[1], normal[2]));
             }
-            for uv in reader.read_tex_coords(0).unwrap().into_f32() {
+            for uv in reader
+                .read_tex_coords(0)
+                .ok_or_else(|| missing("texture coordinates"))?
+                .into_f32()
+            {
                 dst.uvs.push(vec2(uv[0], uv[1]));
             }
 
@@ -80,9 +166,9 @@ impl Loader<Scene> for GltfLoader {
                 .metallic_roughness_texture()
                 .map(|t| Texture::image(texture_offset as u32 + t.texture().index() as u32))
                 .unwrap_or(Texture::constant(vec3(
-                    mr_model.metallic_factor(),
-                    mr_model.roughness_factor(),
                     0.,
+                    mr_model.roughness_factor(),
+                    mr_model.metallic_factor(),
                 )));
             // let emission = material
             //     .emissive_texture()
@@ -101,32 +187,73 @@ impl Loader<Scene> for GltfLoader {
                 })
                 .unwrap_or(Texture::constant(vec3(0., 0., 0.)));
 
+            let ior = material.ior().unwrap_or(1.5);
+
             dst.materials.push(Material {
                 base_color,
                 metallic_roughness,
                 normal,
                 transmission,
+                ior,
             })
         }
 
         let instance_offset = dst.instances.len();
         for node in gltf.nodes() {
             if let Some(camera) = node.camera() {
-                if let gltf::camera::Projection::Perspective(proj) = camera.projection() {
-                    let to_world = Mat4::from_cols_array_2d(&node.transform().matrix());
-                    dst.cameras.push(Camera::perspective(
+                let to_world = Mat4::from_cols_array_2d(&node.transform().matrix());
+                let extras = CameraExtras::read(&camera, &node)?;
+                let (near_clip, far_clip) = (0.001, 10000.);
+                // glTF cameras only specify their vertical field of view
+                let camera = match (camera.projection(), extras.projection) {
+                    (Projection::Orthographic(proj), _) => Camera::orthographic(
+                        to_world,
+                        proj.xmag(),
+                        proj.ymag(),
+                        SensorFit::Vertical,
+                        near_clip,
+                        far_clip,
+                    ),
+                    (Projection::Perspective(_), Some(PanoramicProjection::Equirectangular)) => {
+                        Camera::equirectangular(to_world, near_clip, far_clip)
+                    }
+                    (Projection::Perspective(proj), Some(PanoramicProjection::Fisheye)) => {
+                        Camera::fisheye(
+                            to_world,
+                            proj.yfov(),
+                            SensorFit::Overscan,
+                            near_clip,
+                            far_clip,
+                        )
+                    }
+                    (Projection::Perspective(proj), None) => Camera::perspective(
                         to_world,
                         proj.yfov(),
-                        proj.aspect_ratio().unwrap_or(1.),
-                        0.001,
-                        10000.,
-                    ));
-                }
+                        proj.aspect_ratio().unwrap_or(0.),
+                        SensorFit::Vertical,
+                        near_clip,
+                        far_clip,
+                    )
+                    .with_thin_lens(
+                        extras.aperture_radius,
+                        extras.focus_distance,
+                        extras.aperture_blades,
+                        extras.aperture_rotation.to_radians(),
+                    ),
+                };
+                dst.cameras.push(camera);
             }
             if let Some(mesh) = node.mesh() {
                 let matrix = node.transform().matrix();
                 let mut emitter = -1;
-                let material = mesh.primitives().next().unwrap().material();
+                let material = mesh
+                    .primitives()
+                    .next()
+                    .map(|primitive| primitive.material())
+                    .ok_or_else(|| anyhow!("Mesh {} has no primitives", mesh.index()))?;
+                let material_index = material
+                    .index()
+                    .ok_or_else(|| anyhow!("Mesh {} uses the default material", mesh.index()))?;
 
                 if material.emissive_texture().is_some()
                     || material.emissive_factor() != [0., 0., 0.]
@@ -143,7 +270,7 @@ impl Loader<Scene> for GltfLoader {
                 dst.instances.push(Instance {
                     to_world: Mat4::from_cols_array_2d(&matrix),
                     mesh: mesh_offset as u32 + mesh.index() as u32,
-                    material: material_offset as u32 + material.index().unwrap() as u32,
+                    material: material_offset as u32 + material_index as u32,
                     emitter,
                 });
                 if emitter >= 0 {
@@ -151,6 +278,6 @@ impl Loader<Scene> for GltfLoader {
                 }
             }
         }
-        instance_offset
+        Ok(instance_offset)
     }
 }
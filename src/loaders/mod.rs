@@ -4,5 +4,9 @@ pub use self::gltf::*;
 use std::path::Path;
 
 pub trait Loader<T> {
-    fn append(&self, path: impl AsRef<Path>, dst: &mut T) -> usize;
+    ///
+    /// Appends the contents of the file at `path` to `dst` and returns the index of the first
+    /// instance added.
+    ///
+    fn append(&self, path: impl AsRef<Path>, dst: &mut T) -> anyhow::Result<usize>;
 }
@@ -1,43 +1,87 @@
 mod accel;
 mod array;
+mod bvh;
+mod cli;
+mod controller;
+mod film;
 mod integrator;
 mod loaders;
 mod pipelines;
-// mod renderer;
+mod reference;
+mod renderer;
 mod sbt;
 mod scene;
+mod settings;
+mod viewer;
 mod workqueue;
 
-use glam::*;
+use anyhow::bail;
 use screen_13::prelude::*;
+use std::process::ExitCode;
+use std::sync::Arc;
 
-use self::integrator::WavefrontPathIntegrator;
+use self::cli::Args;
 use self::loaders::Loader;
 use self::scene::Scene;
+use self::viewer::Viewer;
 
-fn main() {
+fn main() -> ExitCode {
     pretty_env_logger::init();
 
-    let sc13 = EventLoop::new()
-        .debug(true)
-        .ray_tracing(true)
-        .build()
-        .unwrap();
-    let device = &sc13.device;
-    let mut cache = HashPool::new(device);
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", cli::USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("error: {:#}\n\n{}", err, cli::USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {:#}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    let integrator = WavefrontPathIntegrator::new(device);
+fn run(args: &Args) -> anyhow::Result<()> {
+    let settings = &args.settings;
 
     let mut scene = Scene::default();
     let loader = loaders::GltfLoader::default();
-    loader.append("assets/cornell-box.gltf", &mut scene);
+    loader.append(&settings.scene, &mut scene)?;
+    if settings.camera as usize >= scene.cameras.len() {
+        bail!(
+            "Camera {} requested, but {} has {} cameras",
+            settings.camera,
+            settings.scene.display(),
+            scene.cameras.len()
+        );
+    }
 
-    let mut graph = RenderGraph::new();
+    if !args.headless {
+        let event_loop = EventLoop::new()
+            .debug(args.debug)
+            .ray_tracing(true)
+            .build()?;
+        let viewer = Viewer::new(&event_loop.device, settings.clone())?;
+        return viewer.run(event_loop, scene);
+    }
 
-    // scene.update(device, &mut cache, &mut graph);
+    let cfg = DriverConfig::new()
+        .debug(args.debug)
+        .ray_tracing(true)
+        .build();
+    let device = Arc::new(Device::create_headless(cfg)?);
 
-    integrator.render(&mut scene, uvec2(4, 4));
+    let mut integrator = settings.integrator.create(&device)?;
+    let output = integrator.render(&mut scene, settings)?;
+    output.save(&device, &settings.outputs)?;
 
-    graph.resolve();
-    unsafe { device.device_wait_idle().unwrap() };
+    Ok(())
 }
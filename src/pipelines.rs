@@ -1,3 +1,4 @@
+use anyhow::Context;
 use screen_13::prelude::*;
 
 use crate::sbt::{SbtBuffer, SbtBufferInfo};
@@ -14,19 +15,21 @@ pub struct CompileResult {
 }
 
 const SPV_DIR: &str = "./assets/spv";
-static SHADERS: once_cell::sync::Lazy<CompileResult> = once_cell::sync::Lazy::new(|| {
-    serde_json::from_str(
-        std::fs::read_to_string("./assets/spv/shaders.json")
-            .unwrap()
-            .as_str(),
-    )
-    .unwrap()
-});
+static SHADERS: once_cell::sync::OnceCell<CompileResult> = once_cell::sync::OnceCell::new();
 
-fn load_spv(entry_name: &str) -> Vec<u8> {
-    let path = Path::new(SPV_DIR).join(&SHADERS.entry_to_module[entry_name]);
-    let spv = std::fs::read(path).unwrap();
-    spv
+fn load_spv(entry_name: &str) -> anyhow::Result<Vec<u8>> {
+    let shaders = SHADERS.get_or_try_init(|| -> anyhow::Result<_> {
+        let path = Path::new(SPV_DIR).join("shaders.json");
+        let shaders = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(serde_json::from_str(&shaders)?)
+    })?;
+    let module = shaders
+        .entry_to_module
+        .get(entry_name)
+        .with_context(|| format!("No shader module contains {}", entry_name))?;
+    let path = Path::new(SPV_DIR).join(module);
+    std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))
 }
 
 pub struct RTPipeline {
@@ -35,7 +38,7 @@ pub struct RTPipeline {
 }
 
 impl RTPipeline {
-    pub fn new(device: &Arc<Device>, rgen: &str, rchit: &str, rmiss: &str) -> Self {
+    pub fn new(device: &Arc<Device>, rgen: &str, rchit: &str, rmiss: &str) -> anyhow::Result<Self> {
         let ppl = Arc::new(
             RayTracePipeline::create(
                 device,
@@ -43,28 +46,29 @@ impl RTPipeline {
                     .max_ray_recursion_depth(2)
                     .build(),
                 [
-                    Shader::new_ray_gen(load_spv(rgen)).entry_name(rgen.into()),
-                    Shader::new_closest_hit(load_spv("rchit")).entry_name("rchit".into()),
-                    Shader::new_miss(load_spv("rmiss")).entry_name("rmiss".into()),
-                    // Shader::new_miss(load_spv("rmiss_shadow")).entry_name("rmiss_shadow".into()),
+                    Shader::new_ray_gen(load_spv(rgen)?).entry_name(rgen.into()),
+                    Shader::new_closest_hit(load_spv(rchit)?).entry_name(rchit.into()),
+                    Shader::new_miss(load_spv(rmiss)?).entry_name(rmiss.into()),
+                    Shader::new_miss(load_spv("rmiss_shadow")?).entry_name("rmiss_shadow".into()),
                 ],
                 [
                     RayTraceShaderGroup::new_general(0),
                     RayTraceShaderGroup::new_triangles(1, None),
                     RayTraceShaderGroup::new_general(2),
-                    // RayTraceShaderGroup::new_general(3),
+                    RayTraceShaderGroup::new_general(3),
                 ],
             )
-            .unwrap(),
+            .with_context(|| format!("Failed to create the pipeline of {}", rgen))?,
         );
+        // Miss index 0 is the regular miss shader, 1 the shadow miss shader
         let sbt_info = SbtBufferInfo {
             rgen_index: 0,
             hit_indices: &[1],
-            miss_indices: &[2],
+            miss_indices: &[2, 3],
             callable_indices: &[],
         };
-        let sbt = SbtBuffer::create(device, sbt_info, &ppl).unwrap();
-        Self { sbt, ppl }
+        let sbt = SbtBuffer::create(device, sbt_info, &ppl)?;
+        Ok(Self { sbt, ppl })
     }
     pub fn ppl(&self) -> &Arc<RayTracePipeline> {
         &self.ppl
@@ -73,15 +77,15 @@ impl RTPipeline {
 
 pub struct CPipeline(Arc<ComputePipeline>);
 impl CPipeline {
-    pub fn new(device: &Arc<Device>, fname: &str) -> Self {
-        Self(Arc::new(
+    pub fn new(device: &Arc<Device>, fname: &str) -> anyhow::Result<Self> {
+        Ok(Self(Arc::new(
             ComputePipeline::create(
                 device,
                 ComputePipelineInfo::default(),
-                Shader::new_compute(load_spv(fname)).entry_name(fname.into()),
+                Shader::new_compute(load_spv(fname)?).entry_name(fname.into()),
             )
-            .unwrap(),
-        ))
+            .with_context(|| format!("Failed to create the pipeline of {}", fname))?,
+        )))
     }
     pub fn ppl(&self) -> &Arc<ComputePipeline> {
         &self.0
@@ -0,0 +1,507 @@
+use common::mis::MisHeuristic;
+use common::path;
+use common::sampler::{PathSampler, Sampler, SamplerState, SamplerType};
+use common::*;
+use glam::*;
+use screen_13::prelude::*;
+use std::sync::Arc;
+
+use crate::array::Array;
+use crate::bvh::Bvh;
+use crate::film;
+use crate::integrator::Integrator;
+use crate::scene::Scene;
+use crate::settings::{CropWindow, RenderOutput, RenderSettings};
+
+///
+/// Textures of the scene on the host, sampled with nearest neighbour lookups and repeat
+/// addressing.
+///
+struct HostTextures {
+    images: Vec<image::Rgba32FImage>,
+}
+
+impl TextureSource for HostTextures {
+    fn sample(&self, texture: u32, uv: Vec2) -> Vec4 {
+        let image = &self.images[texture as usize];
+        let size = uvec2(image.width(), image.height());
+        let uv = uv - uv.floor();
+        let p = (uv * size.as_vec2()).as_uvec2().min(size - 1);
+        Vec4::from(image.get_pixel(p.x, p.y).0)
+    }
+}
+
+///
+/// Everything a path needs to access of the scene.
+///
+struct SceneRef<'a> {
+    scene: &'a Scene,
+    camera: Camera,
+    bvh: Bvh,
+    textures: HostTextures,
+    emitter_cdfs: Vec<f32>,
+}
+
+///
+/// Path tracer running on the CPU, sharing the BSDF, emitter, camera and sampler code with the
+/// wavefront kernels.
+/// It follows the same estimator as `WavefrontPathIntegrator` and serves as a reference for
+/// validating its output.
+///
+pub struct ReferenceIntegrator {
+    pub max_depth: u32,
+    pub rr_depth: u32,
+    pub mis: MisHeuristic,
+    pub seed: u32,
+    pub sampler: SamplerType,
+    pub sensor_fit: Option<SensorFit>, // Replaces the fit of the camera if set
+}
+
+impl Default for ReferenceIntegrator {
+    fn default() -> Self {
+        Self {
+            max_depth: 8,
+            rr_depth: 2,
+            mis: MisHeuristic::Power,
+            seed: 0,
+            sampler: SamplerType::Independent,
+            sensor_fit: None,
+        }
+    }
+}
+
+impl ReferenceIntegrator {
+    ///
+    /// Renders `spp` samples per pixel through the camera with index `camera` and returns their
+    /// mean.
+    ///
+    pub fn render(
+        &self,
+        scene: &mut Scene,
+        camera: usize,
+        size: UVec2,
+        spp: u32,
+    ) -> image::Rgba32FImage {
+        self.render_region(scene, camera, size, CropWindow::full(size), 0, spp)
+    }
+    ///
+    /// Renders the samples `sample_offset..sample_offset + spp` of the region `crop` of a film of
+    /// `size` and returns their mean, an image of the size of the region.
+    ///
+    pub fn render_region(
+        &self,
+        scene: &mut Scene,
+        camera: usize,
+        size: UVec2,
+        crop: CropWindow,
+        sample_offset: u32,
+        spp: u32,
+    ) -> image::Rgba32FImage {
+        let emitter_cdfs = scene.build_emitter_cdfs();
+        let scene = SceneRef {
+            camera: scene.cameras[camera]
+                .with_fit(self.sensor_fit.map_or(SENSOR_FIT_CAMERA, |fit| fit as u32)),
+            bvh: Bvh::new(scene),
+            textures: HostTextures {
+                images: scene.textures.iter().map(|img| img.to_rgba32f()).collect(),
+            },
+            emitter_cdfs,
+            scene,
+        };
+
+        let mut image = image::Rgba32FImage::new(crop.width, crop.height);
+
+        // Render rows in parallel
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let rows_per_thread = ((crop.height as usize + threads - 1) / threads).max(1);
+        let row_len = crop.width as usize * 4;
+        std::thread::scope(|s| {
+            for (chunk_idx, chunk) in image.chunks_mut(rows_per_thread * row_len).enumerate() {
+                let scene = &scene;
+                s.spawn(move || {
+                    for (row_idx, row) in chunk.chunks_mut(row_len).enumerate() {
+                        let y = (chunk_idx * rows_per_thread + row_idx) as u32;
+                        for x in 0..crop.width {
+                            let pixel = uvec2(crop.x + x, crop.y + y);
+                            let mut radiance = Vec3::ZERO;
+                            for sample in sample_offset..sample_offset + spp {
+                                radiance += self.sample_pixel(scene, pixel, size, sample);
+                            }
+                            let radiance = radiance / spp.max(1) as f32;
+                            row[x as usize * 4..x as usize * 4 + 4]
+                                .copy_from_slice(&radiance.extend(1.).to_array());
+                        }
+                    }
+                });
+            }
+        });
+
+        image
+    }
+    ///
+    /// Traces one path through `pixel`, the equivalent of one pixel of the wavefront.
+    ///
+    fn sample_pixel(&self, scene: &SceneRef, pixel: UVec2, size: UVec2, sample: u32) -> Vec3 {
+        let SceneRef {
+            scene: s,
+            camera,
+            bvh,
+            textures,
+            emitter_cdfs,
+        } = scene;
+
+        let mut sampler = PathSampler::new(
+            self.sampler as u32,
+            SamplerState::new(pixel, sample, self.seed),
+        );
+        let jitter = sampler.next_2d();
+        let sample_pos = (pixel.as_vec2() + jitter) / size.as_vec2();
+        let lens_sample = sampler.next_2d();
+
+        let (mut ray, weight) =
+            camera.sample_ray(sample_pos, lens_sample, size.x as f32 / size.y as f32);
+        if weight == 0. {
+            return Vec3::ZERO;
+        }
+        let mut throughput = Vec3::splat(weight);
+        let mut bsdf_pdf = 0.;
+        let mut radiance = Vec3::ZERO;
+
+        for depth in 0..self.max_depth {
+            let hit = match bvh.intersect_closest(&ray) {
+                Some(hit) => hit,
+                None => break,
+            };
+            let si = SurfaceInteraction::from_hit(
+                &ray,
+                hit.instance,
+                hit.primitive,
+                hit.barycentric,
+                hit.dist,
+                &s.instances,
+                &s.meshes,
+                &s.indices,
+                &s.normals,
+                &s.uvs,
+            );
+
+            let shading = path::shade_vertex(
+                &si,
+                throughput,
+                depth,
+                bsdf_pdf,
+                &mut sampler,
+                self.mis as u32,
+                self.max_depth,
+                self.rr_depth,
+                &s.instances,
+                &s.meshes,
+                &s.indices,
+                &s.positions,
+                &s.uvs,
+                &s.emitters,
+                emitter_cdfs,
+                &s.materials,
+                textures,
+            );
+            radiance += shading.emission;
+            if shading.direct != Vec3::ZERO && !bvh.intersect_any(&shading.shadow_ray) {
+                radiance += shading.direct;
+            }
+
+            if !shading.continues {
+                break;
+            }
+            ray = shading.ray;
+            throughput = shading.throughput;
+            bsdf_pdf = shading.bsdf_pdf;
+        }
+
+        radiance
+    }
+}
+
+///
+/// Mean of the samples the reference integrator has accumulated, and its copy on the GPU.
+///
+struct Accumulation {
+    film_size: UVec2,
+    crop: CropWindow,
+    spp: u32,
+    mean: image::Rgba32FImage,
+    image: Arc<Image>,
+}
+
+///
+/// Runs the `ReferenceIntegrator` behind the `Integrator` interface, uploading the image it
+/// renders on the CPU so that it can be saved and displayed like the images of the GPU
+/// integrators.
+///
+pub struct ReferenceRenderer {
+    pub integrator: ReferenceIntegrator,
+    device: Arc<Device>,
+    accumulation: Option<Accumulation>,
+}
+
+impl ReferenceRenderer {
+    pub fn new(device: &Arc<Device>) -> Self {
+        Self {
+            integrator: ReferenceIntegrator::default(),
+            device: device.clone(),
+            accumulation: None,
+        }
+    }
+}
+
+impl Integrator for ReferenceRenderer {
+    fn render(
+        &mut self,
+        scene: &mut Scene,
+        settings: &RenderSettings,
+    ) -> anyhow::Result<RenderOutput> {
+        self.reset();
+        let color = self.accumulate(scene, settings, settings.spp)?;
+        Ok(RenderOutput {
+            color,
+            variance: None,
+            normal: None,
+            position: None,
+        })
+    }
+    fn accumulate(
+        &mut self,
+        scene: &mut Scene,
+        settings: &RenderSettings,
+        spp: u32,
+    ) -> anyhow::Result<Arc<Image>> {
+        self.integrator = ReferenceIntegrator {
+            max_depth: settings.max_depth,
+            rr_depth: settings.rr_depth,
+            mis: settings.mis,
+            seed: settings.seed,
+            sampler: settings.sampler,
+            sensor_fit: settings.sensor_fit,
+        };
+        let film_size = settings.size();
+        let crop = settings.crop_window();
+
+        let mut accumulation = match self.accumulation.take() {
+            Some(accumulation)
+                if accumulation.film_size == film_size && accumulation.crop == crop =>
+            {
+                accumulation
+            }
+            _ => Accumulation {
+                film_size,
+                crop,
+                spp: 0,
+                mean: image::Rgba32FImage::new(crop.width, crop.height),
+                image: film::create_image(&self.device, crop.size()),
+            },
+        };
+
+        let mean = self.integrator.render_region(
+            scene,
+            settings.camera as usize,
+            film_size,
+            crop,
+            accumulation.spp,
+            spp,
+        );
+        let weight = spp as f32 / (accumulation.spp + spp).max(1) as f32;
+        for (accum, value) in accumulation.mean.iter_mut().zip(mean.iter()) {
+            *accum += (value - *accum) * weight;
+        }
+        accumulation.spp += spp;
+
+        let mut cache = HashPool::new(&self.device);
+        let mut graph = RenderGraph::new();
+        let staging = Array::from_slice(
+            &self.device,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            accumulation.mean.as_raw(),
+        );
+        let staging = graph.bind_node(staging.buf());
+        let image = graph.bind_node(&accumulation.image);
+        graph.copy_buffer_to_image(staging, image);
+
+        let mut cmd_buf = graph.resolve().submit(&mut cache, 0)?;
+        cmd_buf.wait_until_executed()?;
+
+        Ok(self.accumulation.insert(accumulation).image.clone())
+    }
+    fn reset(&mut self) {
+        if let Some(accumulation) = &mut self.accumulation {
+            accumulation.spp = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::{FRAC_1_PI, FRAC_PI_2, PI};
+
+    const LIGHT_RADIANCE: Vec3 = vec3(1., 2., 4.);
+    const LIGHT_HEIGHT: f32 = 1.;
+    const LIGHT_HALF_SIZE: f32 = 1.;
+    const ALBEDO: f32 = 0.5;
+
+    fn material(base_color: f32) -> Material {
+        // An index of refraction of 1 turns off the specular lobe, which leaves a Lambertian
+        // surface
+        Material {
+            base_color: Texture::constant(Vec3::splat(base_color)),
+            metallic_roughness: Texture::constant(vec3(0., 1., 0.)),
+            transmission: Texture::constant(Vec3::ZERO),
+            ior: 1.,
+            ..Default::default()
+        }
+    }
+
+    ///
+    /// Large Lambertian floor at z = 0 lit by a square area light facing down from above its
+    /// center. The first camera looks down at the floor below the light, the second one up into
+    /// the light and the third one along the floor, seeing nothing.
+    ///
+    fn scene() -> Scene {
+        let mut scene = Scene {
+            indices: vec![0, 1, 2, 0, 2, 3],
+            positions: vec![
+                vec3(-1., -1., 0.),
+                vec3(1., -1., 0.),
+                vec3(1., 1., 0.),
+                vec3(-1., 1., 0.),
+            ],
+            normals: vec![Vec3::Z; 4],
+            uvs: vec![Vec2::ZERO; 4],
+            meshes: vec![Mesh {
+                indices: 0,
+                indices_count: 6,
+                positions: 0,
+                normals: 0,
+                uvs: 0,
+            }],
+            instances: vec![
+                Instance {
+                    to_world: Mat4::from_scale(vec3(100., 100., 1.)),
+                    mesh: 0,
+                    material: 0,
+                    emitter: -1,
+                },
+                Instance {
+                    to_world: Mat4::from_translation(vec3(0., 0., LIGHT_HEIGHT))
+                        * Mat4::from_rotation_x(PI)
+                        * Mat4::from_scale(vec3(LIGHT_HALF_SIZE, LIGHT_HALF_SIZE, 1.)),
+                    mesh: 0,
+                    material: 1,
+                    emitter: 0,
+                },
+            ],
+            emitters: vec![Emitter::area(Texture::constant(LIGHT_RADIANCE), 1)],
+            materials: vec![material(ALBEDO), material(0.)],
+            ..Default::default()
+        };
+
+        let eye = Mat4::from_translation(vec3(0., 0., LIGHT_HEIGHT * 0.5));
+        for to_world in [
+            eye,
+            eye * Mat4::from_rotation_x(PI),
+            eye * Mat4::from_rotation_x(FRAC_PI_2),
+        ] {
+            scene.cameras.push(Camera::orthographic(
+                to_world,
+                0.01,
+                0.01,
+                SensorFit::Vertical,
+                0.001,
+                100.,
+            ));
+        }
+        scene
+    }
+
+    fn mean(image: &image::Rgba32FImage) -> Vec3 {
+        let sum = image
+            .pixels()
+            .fold(Vec3::ZERO, |sum, p| sum + Vec4::from(p.0).xyz());
+        sum / image.pixels().len() as f32
+    }
+
+    fn assert_close(value: Vec3, expected: Vec3, tolerance: f32) {
+        assert!(
+            ((value - expected).abs() / expected.max(Vec3::splat(1e-6))).max_element() <= tolerance
+                || (value - expected).abs().max_element() <= 1e-6,
+            "{value} != {expected}"
+        );
+    }
+
+    #[test]
+    fn emitter_seen_directly() {
+        let mut scene = scene();
+        let integrator = ReferenceIntegrator::default();
+
+        let image = integrator.render(&mut scene, 1, uvec2(4, 4), 4);
+        assert_close(mean(&image), LIGHT_RADIANCE, 1e-5);
+    }
+
+    #[test]
+    fn nothing_seen() {
+        let mut scene = scene();
+        let integrator = ReferenceIntegrator::default();
+
+        let image = integrator.render(&mut scene, 2, uvec2(4, 4), 4);
+        assert_eq!(mean(&image), Vec3::ZERO);
+    }
+
+    #[test]
+    fn floor_lit_by_square_light() {
+        // Form factor from a point to a parallel square centered above it, the sum of the
+        // form factors to its four quadrants
+        let x = LIGHT_HALF_SIZE / LIGHT_HEIGHT;
+        let s = x / (1. + x * x).sqrt();
+        let form_factor = 4. * FRAC_1_PI * s * s.atan();
+        let expected = LIGHT_RADIANCE * ALBEDO * form_factor;
+
+        for mis in [
+            MisHeuristic::None,
+            MisHeuristic::Balance,
+            MisHeuristic::Power,
+        ] {
+            let mut scene = scene();
+            let integrator = ReferenceIntegrator {
+                mis,
+                ..Default::default()
+            };
+
+            let image = integrator.render(&mut scene, 0, uvec2(8, 8), 256);
+            assert_close(mean(&image), expected, 0.02);
+        }
+    }
+
+    #[test]
+    fn regions_and_sample_ranges_continue_the_full_render() {
+        let mut scene = scene();
+        let integrator = ReferenceIntegrator::default();
+        let size = uvec2(8, 8);
+        let full = integrator.render(&mut scene, 0, size, 4);
+
+        let crop = CropWindow {
+            x: 2,
+            y: 3,
+            width: 4,
+            height: 5,
+        };
+        let region = integrator.render_region(&mut scene, 0, size, crop, 0, 4);
+        for (x, y, pixel) in region.enumerate_pixels() {
+            assert_eq!(pixel, full.get_pixel(crop.x + x, crop.y + y));
+        }
+
+        let first = integrator.render_region(&mut scene, 0, size, CropWindow::full(size), 0, 2);
+        let second = integrator.render_region(&mut scene, 0, size, CropWindow::full(size), 2, 2);
+        for ((a, b), expected) in first.pixels().zip(second.pixels()).zip(full.pixels()) {
+            let mean = (Vec4::from(a.0) + Vec4::from(b.0)) * 0.5;
+            assert!(mean.abs_diff_eq(Vec4::from(expected.0), 1e-5));
+        }
+    }
+}
@@ -1,116 +1,123 @@
-use crate::array::Array;
-use crate::sbt::{SbtBuffer, SbtBufferInfo};
+use crate::integrator::Integrator;
+use crate::pipelines::RTPipeline;
 use crate::scene::{Scene, SceneBinding};
+use crate::settings::{CropWindow, RenderOutput, RenderSettings};
+use common::mis::MisHeuristic;
+use common::sampler::SamplerType;
 use common::*;
-use crevice::std140::AsStd140;
-use glam;
+use glam::*;
 use screen_13::prelude::*;
-use std::fmt::Write;
-use std::fs;
-use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-// const SHADERS: &[u8] = include_bytes!(env!("rust_shaders.spv"));
-
 pub struct GBuffer {
     pub color: AnyImageNode,
     pub normal: AnyImageNode,
     pub position: AnyImageNode,
 }
 
-pub struct RTPipeline {
-    pub sbt: SbtBuffer,
-    pub ppl: Arc<RayTracePipeline>,
+///
+/// Images the megakernel accumulates into across launches.
+///
+struct Accumulation {
+    film_size: UVec2,
+    crop: CropWindow,
+    spp: u32,
+    color: Arc<Image>,
+    normal: Arc<Image>,
+    position: Arc<Image>,
 }
 
-impl RTPipeline {
-    pub fn new(device: &Arc<Device>, rgen: &str) -> Self {
-        let ppl = Arc::new(
-            RayTracePipeline::create(
+impl Accumulation {
+    fn new(device: &Arc<Device>, film_size: UVec2, crop: CropWindow) -> anyhow::Result<Self> {
+        let size = crop.size();
+        let create_image = || -> anyhow::Result<Arc<Image>> {
+            let image = Image::create(
                 device,
-                RayTracePipelineInfo::new()
-                    .max_ray_recursion_depth(2)
-                    .build(),
-                [
-                    Shader::new_ray_gen(SHADERS).entry_name(rgen.into()),
-                    Shader::new_closest_hit(SHADERS).entry_name("rchit".into()),
-                    Shader::new_miss(SHADERS).entry_name("rmiss".into()),
-                    Shader::new_miss(SHADERS).entry_name("rmiss_shadow".into()),
-                ],
-                [
-                    RayTraceShaderGroup::new_general(0),
-                    RayTraceShaderGroup::new_triangles(1, None),
-                    RayTraceShaderGroup::new_general(2),
-                    RayTraceShaderGroup::new_general(3),
-                ],
-            )
-            .unwrap(),
-        );
-        let sbt_info = SbtBufferInfo {
-            rgen_index: 0,
-            hit_indices: &[1],
-            miss_indices: &[2, 3],
-            callable_indices: &[],
+                ImageInfo::new_2d(
+                    vk::Format::R32G32B32A32_SFLOAT,
+                    size.x,
+                    size.y,
+                    vk::ImageUsageFlags::STORAGE
+                        | vk::ImageUsageFlags::SAMPLED
+                        | vk::ImageUsageFlags::TRANSFER_SRC,
+                ),
+            )?;
+            Ok(Arc::new(image))
         };
-        let sbt = SbtBuffer::create(device, sbt_info, &ppl).unwrap();
-        Self { sbt, ppl }
+        Ok(Self {
+            film_size,
+            crop,
+            spp: 0,
+            color: create_image()?,
+            normal: create_image()?,
+            position: create_image()?,
+        })
     }
 }
 
+///
+/// Megakernel path tracer, tracing whole paths in a single ray generation shader.
+///
 pub struct PTRenderer {
     ppl: RTPipeline,
+    device: Arc<Device>,
+    accumulation: Option<Accumulation>,
+    pub max_depth: u32,
+    pub rr_depth: u32,
+    pub mis: MisHeuristic,
+    pub sampler: SamplerType,
+    pub sensor_fit: Option<SensorFit>, // Replaces the fit of the camera if set
 }
 
 impl PTRenderer {
-    pub fn new(device: &Arc<Device>) -> Self {
-        Self {
-            ppl: RTPipeline::new(device, "path_trace"),
-        }
+    pub fn new(device: &Arc<Device>) -> anyhow::Result<Self> {
+        Ok(Self {
+            ppl: RTPipeline::new(device, "path_trace", "rchit", "rmiss")?,
+            device: device.clone(),
+            accumulation: None,
+            max_depth: 8,
+            rr_depth: 2,
+            mis: MisHeuristic::Power,
+            sampler: SamplerType::Independent,
+            sensor_fit: None,
+        })
     }
+    ///
+    /// Traces `spp` samples per pixel of the region `crop` of a film of `film_size` into
+    /// `gbuffer`, which has the size of the region.
+    /// The color is averaged with the `sample_offset` samples already in it.
+    ///
     pub fn bind_and_render(
         &self,
         scene: &SceneBinding,
-        //image: impl Into<AnyImageNode>,
+        gbuffer: &GBuffer,
         seed: u32,
-        width: u32,
-        height: u32,
+        film_size: UVec2,
+        crop: CropWindow,
         camera: u32,
-        cache: &mut HashPool,
+        sample_offset: u32,
+        spp: u32,
         rgraph: &mut RenderGraph,
-    ) -> GBuffer {
-        #[derive(AsStd140, Debug, Clone, Copy)]
-        struct PushConstant {
-            pub camera: u32,
-            pub max_depth: u32,
-            pub rr_depth: u32,
-            pub seed: u32,
-        }
-        let push_constant = PushConstant {
+    ) {
+        let push_constant = PathTracePc {
             camera,
+            max_depth: self.max_depth,
+            rr_depth: self.rr_depth,
             seed,
-            max_depth: 8,
-            rr_depth: 2,
-        };
-
-        let mut lease_img = || -> AnyImageNode {
-            let img = cache
-                .lease(ImageInfo::new_2d(
-                    vk::Format::R32G32B32A32_SFLOAT,
-                    width,
-                    height,
-                    vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
-                ))
-                .unwrap();
-            rgraph.bind_node(img).into()
+            spp,
+            film_width: film_size.x,
+            film_height: film_size.y,
+            offset_x: crop.x,
+            offset_y: crop.y,
+            sample_offset,
+            mis: self.mis as u32,
+            sampler: self.sampler as u32,
+            sensor_fit: self.sensor_fit.map_or(SENSOR_FIT_CAMERA, |fit| fit as u32),
         };
 
-        let color = lease_img();
-        let position = lease_img();
-        let normal = lease_img();
-
         let mut pass = rgraph
             .begin_pass("Path Tracing Pass")
-            .bind_pipeline(&self.ppl.ppl)
+            .bind_pipeline(self.ppl.ppl())
             .read_descriptor((0, 0), scene.indices)
             .read_descriptor((0, 1), scene.positions)
             .read_descriptor((0, 2), scene.normals)
@@ -120,15 +127,16 @@ impl PTRenderer {
             .read_descriptor((0, 6), scene.emitters)
             .read_descriptor((0, 7), scene.materials)
             .read_descriptor((0, 8), scene.cameras)
-            .read_descriptor((0, 10), scene.accel);
+            .read_descriptor((0, 10), scene.accel)
+            .read_descriptor((0, 11), scene.emitter_cdfs);
 
-        // for (i, texture) in scene.textures.iter().enumerate() {
-        //     pass = pass.read_descriptor((0, 9, [i as _]), *texture);
-        // }
+        for (i, texture) in scene.textures.iter().enumerate() {
+            pass = pass.read_descriptor((0, 9, [i as _]), *texture);
+        }
 
-        pass = pass.write_descriptor((1, 0), color);
-        pass = pass.write_descriptor((1, 1), normal);
-        pass = pass.write_descriptor((1, 2), position);
+        pass = pass.write_descriptor((1, 0), gbuffer.color);
+        pass = pass.write_descriptor((1, 1), gbuffer.normal);
+        pass = pass.write_descriptor((1, 2), gbuffer.position);
 
         let sbt_rgen = self.ppl.sbt.rgen();
         let sbt_miss = self.ppl.sbt.miss();
@@ -136,22 +144,94 @@ impl PTRenderer {
         let sbt_callable = self.ppl.sbt.callable();
 
         pass.record_ray_trace(move |ray_trace, _| {
-            ray_trace.push_constants(push_constant.as_std140().as_bytes());
+            ray_trace.push_constants(bytemuck::cast_slice(&[push_constant]));
             ray_trace.trace_rays(
                 &sbt_rgen,
                 &sbt_miss,
                 &sbt_hit,
                 &sbt_callable,
-                width,
-                height,
+                crop.width,
+                crop.height,
                 1,
             );
         });
+    }
+}
+
+impl Integrator for PTRenderer {
+    fn render(
+        &mut self,
+        scene: &mut Scene,
+        settings: &RenderSettings,
+    ) -> anyhow::Result<RenderOutput> {
+        self.reset();
+        self.accumulate(scene, settings, settings.spp)?;
+
+        let accumulation = self.accumulation.as_ref().unwrap();
+        Ok(RenderOutput {
+            color: accumulation.color.clone(),
+            variance: None,
+            normal: Some(accumulation.normal.clone()),
+            position: Some(accumulation.position.clone()),
+        })
+    }
+    fn accumulate(
+        &mut self,
+        scene: &mut Scene,
+        settings: &RenderSettings,
+        spp: u32,
+    ) -> anyhow::Result<Arc<Image>> {
+        self.max_depth = settings.max_depth;
+        self.rr_depth = settings.rr_depth;
+        self.mis = settings.mis;
+        self.sampler = settings.sampler;
+        self.sensor_fit = settings.sensor_fit;
+        let film_size = settings.size();
+        let crop = settings.crop_window();
+
+        let mut accumulation = match self.accumulation.take() {
+            Some(accumulation)
+                if accumulation.film_size == film_size && accumulation.crop == crop =>
+            {
+                accumulation
+            }
+            _ => Accumulation::new(&self.device, film_size, crop)?,
+        };
+
+        let mut cache = HashPool::new(&self.device);
+        let mut graph = RenderGraph::new();
 
-        GBuffer {
-            color,
-            normal,
-            position,
+        if scene.tlas.is_none() {
+            scene.update(&self.device, &mut cache, &mut graph);
+        }
+        let scene_bindings = scene.bind(&mut graph);
+
+        let gbuffer = GBuffer {
+            color: graph.bind_node(&accumulation.color).into(),
+            normal: graph.bind_node(&accumulation.normal).into(),
+            position: graph.bind_node(&accumulation.position).into(),
+        };
+        self.bind_and_render(
+            &scene_bindings,
+            &gbuffer,
+            settings.seed,
+            film_size,
+            crop,
+            settings.camera,
+            accumulation.spp,
+            spp,
+            &mut graph,
+        );
+
+        graph.resolve().submit(&mut cache, 0)?;
+        unsafe { self.device.device_wait_idle()? };
+
+        accumulation.spp += spp;
+        Ok(self.accumulation.insert(accumulation).color.clone())
+    }
+    fn reset(&mut self) {
+        if let Some(accumulation) = &mut self.accumulation {
+            accumulation.spp = 0;
         }
     }
 }
@@ -28,6 +28,7 @@ pub struct Scene {
     pub instance_data: Option<Array<Instance>>,
     pub mesh_data: Option<Array<Mesh>>,
     pub emitter_data: Option<Array<Emitter>>,
+    pub emitter_cdf_data: Option<Array<f32>>,
     pub material_data: Option<Array<Material>>,
     pub camera_data: Option<Array<Camera>>,
 
@@ -50,19 +51,78 @@ impl Scene {
             self.meshes[mesh_idx + 1].indices as usize - self.meshes[mesh_idx].indices as usize
         }
     }
+    ///
+    /// World space area of a triangle of the given instance.
+    ///
+    pub fn triangle_area(&self, instance: &Instance, primitive: usize) -> f32 {
+        let mesh = &self.meshes[instance.mesh as usize];
+        let idx = mesh.indices as usize + 3 * primitive;
+        let vertex = |i: usize| {
+            let position = self.positions[mesh.positions as usize + self.indices[idx + i] as usize];
+            instance.to_world.transform_point3(position)
+        };
+        let p0 = vertex(0);
+        let p1 = vertex(1);
+        let p2 = vertex(2);
+        0.5 * (p1 - p0).cross(p2 - p0).length()
+    }
+    ///
+    /// Builds the normalized triangle area cdfs of all area emitters and updates their offsets
+    /// and total areas.
+    ///
+    pub fn build_emitter_cdfs(&mut self) -> Vec<f32> {
+        let mut cdfs = vec![];
+        for i in 0..self.emitters.len() {
+            if !self.emitters[i].is_area() {
+                continue;
+            }
+            let instance = self.instances[self.emitters[i].instance as usize];
+            let triangle_count = self.meshes[instance.mesh as usize].indices_count as usize / 3;
+
+            let offset = cdfs.len();
+            let mut area = 0.;
+            for primitive in 0..triangle_count {
+                area += self.triangle_area(&instance, primitive);
+                cdfs.push(area);
+            }
+            if area > 0. {
+                for value in cdfs[offset..].iter_mut() {
+                    *value /= area;
+                }
+            }
+
+            self.emitters[i].cdf = offset as u32;
+            self.emitters[i].area = area;
+        }
+        // Avoid creating an empty buffer
+        if cdfs.is_empty() {
+            cdfs.push(0.);
+        }
+        cdfs
+    }
+    ///
+    /// Writes the cameras to their buffer, which is only created if there is none to reuse.
+    ///
     pub fn update_camera(
         &mut self,
         device: &Arc<Device>,
         cache: &mut HashPool,
         rgraph: &mut RenderGraph,
     ) {
-        self.camera_data = Some(Array::from_slice_staging(
-            &device,
-            cache,
-            rgraph,
-            vk::BufferUsageFlags::STORAGE_BUFFER,
-            &self.cameras,
-        ));
+        match &self.camera_data {
+            Some(camera_data) if camera_data.count() == self.cameras.len() => {
+                camera_data.copy_from_slice(device, cache, rgraph, &self.cameras);
+            }
+            _ => {
+                self.camera_data = Some(Array::from_slice_staging(
+                    &device,
+                    cache,
+                    rgraph,
+                    vk::BufferUsageFlags::STORAGE_BUFFER,
+                    &self.cameras,
+                ));
+            }
+        }
     }
     pub fn upload(&mut self, device: &Arc<Device>, cache: &mut HashPool, rgraph: &mut RenderGraph) {
         self.index_data = Some(Array::from_slice_staging(
@@ -112,6 +172,14 @@ impl Scene {
             vk::BufferUsageFlags::STORAGE_BUFFER,
             &self.meshes,
         ));
+        let emitter_cdfs = self.build_emitter_cdfs();
+        self.emitter_cdf_data = Some(Array::from_slice_staging(
+            &device,
+            cache,
+            rgraph,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            &emitter_cdfs,
+        ));
         self.emitter_data = Some(Array::from_slice_staging(
             &device,
             cache,
@@ -149,11 +217,22 @@ impl Scene {
                 .unwrap();
             self.textures_gpu.as_mut().unwrap().push(img);
         }
+        // The texture arrays of the kernels are always bound, so scenes without textures get a
+        // placeholder
+        if self.textures.is_empty() {
+            let mut img_loader = ImageLoader::new(device).unwrap();
+            let img = image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4]));
+            let img = img_loader
+                .decode_linear(0, &img, screen_13_fx::ImageFormat::R8G8B8A8, 1, 1)
+                .unwrap();
+            self.textures_gpu.as_mut().unwrap().push(img);
+        }
     }
     pub fn update(&mut self, device: &Arc<Device>, cache: &mut HashPool, rgraph: &mut RenderGraph) {
         // Upload to gpu
         self.upload(device, cache, rgraph);
         // Create blases
+        self.blases.clear();
         for instance in self.instances.iter() {
             let mesh = &self.meshes[instance.mesh as usize];
             self.blases.push(Blas::create(
@@ -227,7 +306,9 @@ impl Scene {
             instances: rgraph.bind_node(self.instance_data.as_ref().unwrap().buf()),
             meshes: rgraph.bind_node(self.mesh_data.as_ref().unwrap().buf()),
             emitters: rgraph.bind_node(self.emitter_data.as_ref().unwrap().buf()),
+            emitter_cdfs: rgraph.bind_node(self.emitter_cdf_data.as_ref().unwrap().buf()),
             materials: rgraph.bind_node(self.material_data.as_ref().unwrap().buf()),
+            material_count: self.materials.len() as u32,
             cameras: rgraph.bind_node(self.camera_data.as_ref().unwrap().buf()),
 
             textures: self
@@ -250,8 +331,110 @@ pub struct SceneBinding {
     pub instances: BufferNode,
     pub meshes: BufferNode,
     pub emitters: BufferNode,
+    pub emitter_cdfs: BufferNode,
     pub materials: BufferNode,
+    pub material_count: u32,
     pub cameras: BufferNode,
 
     pub textures: Vec<ImageNode>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///
+    /// Scene with one mesh of two triangles of area 0.5 and 1.5, instanced once per transform.
+    ///
+    fn scene(transforms: &[Mat4]) -> Scene {
+        let mut scene = Scene {
+            indices: vec![0, 1, 2, 0, 3, 4],
+            positions: vec![
+                vec3(0., 0., 0.),
+                vec3(1., 0., 0.),
+                vec3(0., 1., 0.),
+                vec3(0., 0., 1.),
+                vec3(3., 0., 0.),
+            ],
+            meshes: vec![Mesh {
+                indices: 0,
+                indices_count: 6,
+                positions: 0,
+                normals: 0,
+                uvs: 0,
+            }],
+            ..Default::default()
+        };
+        for to_world in transforms {
+            scene.instances.push(Instance {
+                to_world: *to_world,
+                mesh: 0,
+                material: 0,
+                emitter: -1,
+            });
+        }
+        scene
+    }
+
+    fn add_area_emitter(scene: &mut Scene, instance: u32) {
+        scene.instances[instance as usize].emitter = scene.emitters.len() as i32;
+        scene
+            .emitters
+            .push(Emitter::area(Texture::constant(Vec3::ONE), instance));
+    }
+
+    #[test]
+    fn emitter_cdfs_without_emitters() {
+        let mut scene = scene(&[Mat4::IDENTITY]);
+        assert_eq!(scene.build_emitter_cdfs(), vec![0.]);
+
+        scene
+            .emitters
+            .push(Emitter::env(Texture::constant(Vec3::ONE)));
+        assert_eq!(scene.build_emitter_cdfs(), vec![0.]);
+    }
+
+    #[test]
+    fn emitter_cdf_of_single_emitter() {
+        let mut scene = scene(&[Mat4::IDENTITY]);
+        add_area_emitter(&mut scene, 0);
+
+        let cdfs = scene.build_emitter_cdfs();
+        assert_eq!(cdfs, vec![0.25, 1.]);
+        assert_eq!(scene.emitters[0].cdf, 0);
+        assert!((scene.emitters[0].area - 2.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn emitter_cdfs_are_proportional_to_world_space_area() {
+        let mut scene = scene(&[
+            Mat4::IDENTITY,
+            Mat4::from_scale(vec3(2., 2., 2.)),
+            Mat4::from_translation(vec3(0., 5., 0.)) * Mat4::from_scale(vec3(1., 3., 1.)),
+        ]);
+        scene
+            .emitters
+            .push(Emitter::env(Texture::constant(Vec3::ONE)));
+        add_area_emitter(&mut scene, 1);
+        add_area_emitter(&mut scene, 2);
+
+        let cdfs = scene.build_emitter_cdfs();
+        assert_eq!(cdfs.len(), 4);
+
+        for emitter in scene.emitters.iter().filter(|e| e.is_area()) {
+            let instance = scene.instances[emitter.instance as usize];
+            let cdf = &cdfs[emitter.cdf as usize..emitter.cdf as usize + 2];
+
+            assert!(cdf.windows(2).all(|w| w[0] <= w[1]));
+            assert_eq!(cdf[1], 1.);
+
+            let areas = [
+                scene.triangle_area(&instance, 0),
+                scene.triangle_area(&instance, 1),
+            ];
+            assert!((emitter.area - (areas[0] + areas[1])).abs() < 1e-5);
+            assert!((cdf[0] - areas[0] / emitter.area).abs() < 1e-6);
+        }
+        assert!((scene.emitters[1].area - 8.).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,327 @@
+use anyhow::bail;
+use common::mis::MisHeuristic;
+use common::sampler::SamplerType;
+use common::SensorFit;
+use glam::*;
+use screen_13::prelude::*;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::film;
+use crate::integrator::{Integrator, IntersectionBackend, WavefrontPathIntegrator};
+use crate::reference::ReferenceRenderer;
+use crate::renderer::PTRenderer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntegratorType {
+    Wavefront,
+    WavefrontRayQuery,
+    Megakernel,
+    Reference,
+}
+
+impl IntegratorType {
+    pub const ALL: [Self; 4] = [
+        Self::Wavefront,
+        Self::WavefrontRayQuery,
+        Self::Megakernel,
+        Self::Reference,
+    ];
+
+    pub fn create(self, device: &Arc<Device>) -> anyhow::Result<Box<dyn Integrator>> {
+        Ok(match self {
+            Self::Wavefront => Box::new(WavefrontPathIntegrator::new(
+                device,
+                IntersectionBackend::RayTracingPipeline,
+            )?),
+            Self::WavefrontRayQuery => Box::new(WavefrontPathIntegrator::new(
+                device,
+                IntersectionBackend::RayQuery,
+            )?),
+            Self::Megakernel => Box::new(PTRenderer::new(device)?),
+            Self::Reference => Box::new(ReferenceRenderer::new(device)),
+        })
+    }
+}
+
+impl FromStr for IntegratorType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "wavefront" => Self::Wavefront,
+            "wavefront_ray_query" => Self::WavefrontRayQuery,
+            "megakernel" => Self::Megakernel,
+            "reference" => Self::Reference,
+            _ => bail!(
+                "Unknown integrator {:?}, expected wavefront, wavefront_ray_query, megakernel or reference",
+                s
+            ),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(remote = "MisHeuristic", rename_all = "snake_case")]
+enum MisHeuristicDef {
+    None,
+    Balance,
+    Power,
+}
+
+#[derive(Deserialize)]
+#[serde(remote = "SensorFit", rename_all = "snake_case")]
+enum SensorFitDef {
+    Horizontal,
+    Vertical,
+    Fill,
+    Overscan,
+}
+
+fn deserialize_sensor_fit<'de, D>(deserializer: D) -> Result<Option<SensorFit>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(with = "SensorFitDef")] SensorFit);
+
+    let fit = Option::<Wrapper>::deserialize(deserializer)?;
+    Ok(fit.map(|Wrapper(fit)| fit))
+}
+
+#[derive(Deserialize)]
+#[serde(remote = "SamplerType", rename_all = "snake_case")]
+enum SamplerTypeDef {
+    Independent,
+    Halton,
+    Sobol,
+    BlueNoise,
+}
+
+///
+/// Image produced by a render that can be written to an output target.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputBuffer {
+    Color,
+    Variance,
+    Normal,
+    Position,
+}
+
+impl FromStr for OutputBuffer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "color" => Self::Color,
+            "variance" => Self::Variance,
+            "normal" => Self::Normal,
+            "position" => Self::Position,
+            _ => bail!(
+                "Unknown output buffer {:?}, expected color, variance, normal or position",
+                s
+            ),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct OutputTarget {
+    pub buffer: OutputBuffer,
+    pub path: PathBuf,
+}
+
+///
+/// Region of the film to render, in pixels from its top left corner.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct CropWindow {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl CropWindow {
+    pub fn full(size: UVec2) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: size.x,
+            height: size.y,
+        }
+    }
+    ///
+    /// Restricts the window to a film of `size`, keeping at least one pixel.
+    ///
+    pub fn clamp(self, size: UVec2) -> Self {
+        let x = self.x.min(size.x.saturating_sub(1));
+        let y = self.y.min(size.y.saturating_sub(1));
+        Self {
+            x,
+            y,
+            width: self.width.clamp(1, (size.x - x).max(1)),
+            height: self.height.clamp(1, (size.y - y).max(1)),
+        }
+    }
+    pub fn size(&self) -> UVec2 {
+        uvec2(self.width, self.height)
+    }
+}
+
+///
+/// Everything needed to reproduce a render, deserializable from a config file.
+/// Missing fields take their default values.
+///
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RenderSettings {
+    pub scene: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub crop: Option<CropWindow>, // Renders only a region of the film if set
+    pub camera: u32,
+    #[serde(deserialize_with = "deserialize_sensor_fit")]
+    pub sensor_fit: Option<SensorFit>, // Replaces the fit of the camera if set
+    pub spp: u32,
+    pub max_depth: u32,
+    pub rr_depth: u32,
+    pub seed: u32,
+    pub integrator: IntegratorType,
+    #[serde(with = "MisHeuristicDef")]
+    pub mis: MisHeuristic,
+    #[serde(with = "SamplerTypeDef")]
+    pub sampler: SamplerType,
+    pub sort_by_material: bool, // Only used by the wavefront integrators
+    pub outputs: Vec<OutputTarget>,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            scene: "assets/cornell-box.gltf".into(),
+            width: 1024,
+            height: 1024,
+            crop: None,
+            camera: 0,
+            sensor_fit: None,
+            spp: 1,
+            max_depth: 8,
+            rr_depth: 2,
+            seed: 0,
+            integrator: IntegratorType::Wavefront,
+            mis: MisHeuristic::Power,
+            sampler: SamplerType::Independent,
+            sort_by_material: true,
+            outputs: vec![OutputTarget {
+                buffer: OutputBuffer::Color,
+                path: "out/img.exr".into(),
+            }],
+        }
+    }
+}
+
+impl RenderSettings {
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let settings = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&settings)?)
+    }
+    ///
+    /// Size of the film, of which `crop_window` is rendered.
+    ///
+    pub fn size(&self) -> UVec2 {
+        uvec2(self.width, self.height)
+    }
+    pub fn crop_window(&self) -> CropWindow {
+        match self.crop {
+            Some(crop) => crop.clamp(self.size()),
+            None => CropWindow::full(self.size()),
+        }
+    }
+    pub fn wants(&self, buffer: OutputBuffer) -> bool {
+        self.outputs.iter().any(|output| output.buffer == buffer)
+    }
+}
+
+///
+/// Images produced by `Integrator::render`, buffers an integrator does not support are `None`.
+///
+pub struct RenderOutput {
+    pub color: Arc<Image>,
+    pub variance: Option<Arc<Image>>,
+    pub normal: Option<Arc<Image>>,
+    pub position: Option<Arc<Image>>,
+}
+
+impl RenderOutput {
+    pub fn buffer(&self, buffer: OutputBuffer) -> Option<&Arc<Image>> {
+        match buffer {
+            OutputBuffer::Color => Some(&self.color),
+            OutputBuffer::Variance => self.variance.as_ref(),
+            OutputBuffer::Normal => self.normal.as_ref(),
+            OutputBuffer::Position => self.position.as_ref(),
+        }
+    }
+    ///
+    /// Writes the requested buffers to their output targets.
+    /// Fails without writing anything if a buffer was not produced.
+    ///
+    pub fn save(&self, device: &Arc<Device>, outputs: &[OutputTarget]) -> anyhow::Result<()> {
+        if let Some(output) = outputs
+            .iter()
+            .find(|output| self.buffer(output.buffer).is_none())
+        {
+            bail!(
+                "The integrator does not produce {:?}, which is requested for {}",
+                output.buffer,
+                output.path.display()
+            );
+        }
+        for output in outputs {
+            film::save_image(device, self.buffer(output.buffer).unwrap(), &output.path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crop(x: u32, y: u32, width: u32, height: u32) -> CropWindow {
+        CropWindow {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn clamp_keeps_windows_on_the_film() {
+        let size = uvec2(100, 50);
+        assert_eq!(crop(10, 20, 30, 10).clamp(size), crop(10, 20, 30, 10));
+        assert_eq!(CropWindow::full(size).clamp(size), CropWindow::full(size));
+        assert_eq!(crop(0, 0, 200, 100).clamp(size), CropWindow::full(size));
+    }
+
+    #[test]
+    fn clamp_cuts_off_windows_beyond_the_film() {
+        let size = uvec2(100, 50);
+        assert_eq!(crop(90, 40, 20, 20).clamp(size), crop(90, 40, 10, 10));
+        assert_eq!(crop(99, 0, 5, 50).clamp(size), crop(99, 0, 1, 50));
+    }
+
+    #[test]
+    fn clamp_keeps_one_pixel() {
+        let size = uvec2(100, 50);
+        assert_eq!(crop(200, 60, 10, 10).clamp(size), crop(99, 49, 1, 1));
+        assert_eq!(crop(10, 10, 0, 0).clamp(size), crop(10, 10, 1, 1));
+        assert_eq!(crop(10, 10, 5, 5).clamp(UVec2::ZERO), crop(0, 0, 1, 1));
+    }
+}
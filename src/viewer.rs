@@ -0,0 +1,288 @@
+use common::{DisplayPc, SensorFit};
+use screen_13::prelude::*;
+use screen_13_egui::Egui;
+use std::sync::Arc;
+
+use crate::controller::{CameraController, ControllerMode};
+use crate::integrator::{dispatch_size, Integrator};
+use crate::pipelines::CPipeline;
+use crate::scene::Scene;
+use crate::settings::{IntegratorType, RenderSettings};
+
+///
+/// Interactive window showing the accumulation of the integrator, one sample per frame.
+/// The side panel edits the render settings, any change to them restarts the accumulation.
+/// The selected camera is moved by a `CameraController`.
+///
+pub struct Viewer {
+    device: Arc<Device>,
+    settings: RenderSettings,
+    exposure: f32, // In stops, only applied for display
+    integrator: Box<dyn Integrator>,
+    integrator_type: IntegratorType,
+    spp: u32,
+    image: Option<Arc<Image>>,
+    display_ppl: CPipeline,
+    controller: CameraController,
+    controlled_camera: Option<u32>,
+}
+
+impl Viewer {
+    pub fn new(device: &Arc<Device>, settings: RenderSettings) -> anyhow::Result<Self> {
+        Ok(Self {
+            device: device.clone(),
+            integrator: settings.integrator.create(device)?,
+            integrator_type: settings.integrator,
+            settings,
+            exposure: 0.,
+            spp: 0,
+            image: None,
+            display_ppl: CPipeline::new(device, "display")?,
+            controller: CameraController::default(),
+            controlled_camera: None,
+        })
+    }
+    ///
+    /// Runs the viewer until the window is closed or a render fails.
+    ///
+    pub fn run(mut self, event_loop: EventLoop, mut scene: Scene) -> anyhow::Result<()> {
+        let mut egui = Egui::new(&event_loop.device, event_loop.window());
+        let mut cache = HashPool::new(&event_loop.device);
+        let camera_count = scene.cameras.len() as u32;
+        let mut result = Ok(());
+
+        event_loop.run(|frame| {
+            if frame.width == 0 || frame.height == 0 {
+                return;
+            }
+
+            let mut settings = self.settings.clone();
+            settings.width = frame.width;
+            settings.height = frame.height;
+            if let Err(err) = self.update(settings) {
+                result = Err(err);
+                *frame.will_exit = true;
+                return;
+            }
+
+            if let Err(err) = self.update_camera(&mut scene, &mut cache, frame.dt) {
+                result = Err(err);
+                *frame.will_exit = true;
+                return;
+            }
+
+            if self.spp < self.settings.spp {
+                match self.integrator.accumulate(&mut scene, &self.settings, 1) {
+                    Ok(image) => {
+                        self.image = Some(image);
+                        self.spp += 1;
+                    }
+                    Err(err) => {
+                        result = Err(err);
+                        *frame.will_exit = true;
+                        return;
+                    }
+                }
+            }
+
+            match &self.image {
+                Some(image) => {
+                    let image = frame.render_graph.bind_node(image);
+                    if let Err(err) =
+                        self.display(&mut cache, frame.render_graph, image, frame.swapchain_image)
+                    {
+                        result = Err(err);
+                        *frame.will_exit = true;
+                        return;
+                    }
+                }
+                None => frame.render_graph.clear_color_image(frame.swapchain_image),
+            }
+
+            let mut settings = self.settings.clone();
+            let spp = self.spp;
+            let exposure = &mut self.exposure;
+            let controller = &mut self.controller;
+            let mut captured = false;
+            egui.run(
+                frame.window,
+                frame.events,
+                frame.swapchain_image,
+                frame.render_graph,
+                |ctx| {
+                    egui::SidePanel::left("settings").show(ctx, |ui| {
+                        ui.label(format!("{} / {} spp", spp, settings.spp));
+                        ui.add(
+                            egui::Slider::new(&mut settings.spp, 1..=65536)
+                                .logarithmic(true)
+                                .text("spp"),
+                        );
+                        ui.add(
+                            egui::Slider::new(&mut settings.max_depth, 1..=64).text("Max depth"),
+                        );
+                        ui.add(egui::Slider::new(exposure, -10.0..=10.0).text("Exposure"));
+                        egui::ComboBox::from_label("Camera")
+                            .selected_text(settings.camera.to_string())
+                            .show_ui(ui, |ui| {
+                                for camera in 0..camera_count {
+                                    ui.selectable_value(
+                                        &mut settings.camera,
+                                        camera,
+                                        camera.to_string(),
+                                    );
+                                }
+                            });
+                        let fit_name = |fit: Option<SensorFit>| {
+                            fit.map_or("Camera".to_string(), |fit| format!("{:?}", fit))
+                        };
+                        egui::ComboBox::from_label("Sensor fit")
+                            .selected_text(fit_name(settings.sensor_fit))
+                            .show_ui(ui, |ui| {
+                                for fit in [None].into_iter().chain(SensorFit::ALL.map(Some)) {
+                                    ui.selectable_value(
+                                        &mut settings.sensor_fit,
+                                        fit,
+                                        fit_name(fit),
+                                    );
+                                }
+                            });
+                        egui::ComboBox::from_label("Integrator")
+                            .selected_text(format!("{:?}", settings.integrator))
+                            .show_ui(ui, |ui| {
+                                for ty in IntegratorType::ALL {
+                                    ui.selectable_value(
+                                        &mut settings.integrator,
+                                        ty,
+                                        format!("{:?}", ty),
+                                    );
+                                }
+                            });
+                        egui::ComboBox::from_label("Controller")
+                            .selected_text(format!("{:?}", controller.mode))
+                            .show_ui(ui, |ui| {
+                                for mode in ControllerMode::ALL {
+                                    ui.selectable_value(
+                                        &mut controller.mode,
+                                        mode,
+                                        format!("{:?}", mode),
+                                    );
+                                }
+                            });
+                        ui.add(
+                            egui::Slider::new(&mut controller.speed, 0.01..=100.)
+                                .logarithmic(true)
+                                .text("Fly speed"),
+                        );
+                    });
+                    captured = ctx.wants_pointer_input()
+                        || ctx.wants_keyboard_input()
+                        || ctx.is_pointer_over_area();
+                },
+            );
+            if let Err(err) = self.update(settings) {
+                result = Err(err);
+                *frame.will_exit = true;
+                return;
+            }
+
+            for event in frame.events {
+                self.controller.handle_event(event, captured);
+            }
+        })?;
+
+        result
+    }
+    ///
+    /// Takes over `settings`, restarting the accumulation if they differ from the current ones.
+    ///
+    fn update(&mut self, settings: RenderSettings) -> anyhow::Result<()> {
+        if settings == self.settings {
+            return Ok(());
+        }
+        if settings.integrator != self.integrator_type {
+            self.integrator = settings.integrator.create(&self.device)?;
+            self.integrator_type = settings.integrator;
+        }
+        self.settings = settings;
+        self.restart();
+        Ok(())
+    }
+    fn restart(&mut self) {
+        self.integrator.reset();
+        self.spp = 0;
+    }
+    ///
+    /// Applies the input of the camera controller to the selected camera.
+    /// Only the camera buffer is uploaded again, the rest of the scene is left as is.
+    ///
+    fn update_camera(
+        &mut self,
+        scene: &mut Scene,
+        cache: &mut HashPool,
+        dt: f32,
+    ) -> anyhow::Result<()> {
+        let camera = match scene.cameras.get_mut(self.settings.camera as usize) {
+            Some(camera) => camera,
+            None => return Ok(()),
+        };
+        if self.controlled_camera != Some(self.settings.camera) {
+            self.controller.reset(camera.to_world());
+            self.controlled_camera = Some(self.settings.camera);
+        }
+
+        let to_world = match self.controller.update(dt) {
+            Some(to_world) => to_world,
+            None => return Ok(()),
+        };
+        camera.to_world = to_world.to_cols_array_2d();
+
+        // An unbuilt scene uploads its cameras with everything else
+        if scene.tlas.is_some() {
+            let mut graph = RenderGraph::new();
+            scene.update_camera(&self.device, cache, &mut graph);
+            graph.resolve().submit(cache, 0)?;
+        }
+        self.restart();
+        Ok(())
+    }
+    ///
+    /// Applies the exposure and tone mapping to `image` and copies it to the swapchain.
+    ///
+    fn display(
+        &self,
+        cache: &mut HashPool,
+        graph: &mut RenderGraph,
+        image: ImageNode,
+        swapchain_image: SwapchainImageNode,
+    ) -> anyhow::Result<()> {
+        let info = graph.node_info(image);
+        let output = cache.lease(ImageInfo::new_2d(
+            vk::Format::R32G32B32A32_SFLOAT,
+            info.width,
+            info.height,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC,
+        ))?;
+        let output = graph.bind_node(output);
+
+        let pc = DisplayPc {
+            width: info.width,
+            height: info.height,
+            scale: self.exposure.exp2(),
+        };
+
+        graph
+            .begin_pass("Display Pass")
+            .bind_pipeline(self.display_ppl.ppl())
+            .read_descriptor((0, 0), image)
+            .write_descriptor((0, 1), output)
+            .record_compute(move |comp, _| {
+                comp.push_constants(bytemuck::cast_slice(&[pc]));
+                comp.dispatch(dispatch_size(pc.width * pc.height), 1, 1);
+            })
+            .submit_pass();
+
+        // The image has the size of the swapchain, so every texel maps to one pixel
+        graph.blit_image(output, swapchain_image, vk::Filter::NEAREST);
+        Ok(())
+    }
+}
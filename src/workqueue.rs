@@ -1,10 +1,25 @@
+use common::workqueue::{HEADER_WORDS, LEN_WORD};
 use common::*;
 use screen_13::prelude::*;
 use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::Range;
 use std::sync::Arc;
 
 use crate::array::Array;
+use crate::integrator::dispatch_size;
+use crate::pipelines::CPipeline;
 
+/// Size of the `WorkQueue` header in bytes.
+const HEADER_SIZE: usize = HEADER_WORDS as usize * size_of::<u32>();
+/// Byte range of the length within the `WorkQueue` header.
+const LEN_RANGE: Range<u64> = (LEN_WORD as usize * size_of::<u32>()) as u64
+    ..((LEN_WORD + 1) as usize * size_of::<u32>()) as u64;
+
+///
+/// Queue of work items in device-local memory, laid out as the `WorkQueue` of the shaders.
+/// Its length and items are only known on the GPU, `readback` copies them to the host.
+///
 #[derive(Debug)]
 pub struct WorkQueue<T> {
     buf: Arc<Buffer>,
@@ -13,57 +28,480 @@ pub struct WorkQueue<T> {
 }
 
 impl<T: Copy> WorkQueue<T> {
-    pub fn new(device: &Arc<Device>, cap: usize) -> Self {
-        let cap = cap as u32;
-        let len = 0u32;
-
-        let size = std::mem::size_of::<u64>() * 4 + std::mem::size_of::<T>() * cap as usize;
+    ///
+    /// Creates a queue and records the initialization of its header.
+    ///
+    pub fn new(device: &Arc<Device>, graph: &mut RenderGraph, cap: usize) -> Self {
+        let size = HEADER_SIZE + size_of::<T>() * cap;
 
-        let mut buf = Arc::new({
-            let mut buf = Buffer::create(
+        let buf = Arc::new(
+            Buffer::create(
                 device,
-                BufferInfo::new_mappable(
+                BufferInfo::new(
                     size as u64,
-                    vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                    vk::BufferUsageFlags::STORAGE_BUFFER
+                        | vk::BufferUsageFlags::TRANSFER_SRC
+                        | vk::BufferUsageFlags::TRANSFER_DST,
                 ),
             )
-            .unwrap();
-            let slice = Buffer::mapped_slice_mut(&mut buf);
-            slice[0..16].copy_from_slice(bytemuck::cast_slice(&[cap, len, 0, 0]));
-            buf
-        });
+            .unwrap(),
+        );
+
+        let buf_node = graph.bind_node(&buf);
+        let cap_size = size_of::<u32>() as u64;
+        graph.fill_buffer_region(buf_node, cap as u32, 0..cap_size);
+        graph.fill_buffer_region(buf_node, 0, cap_size..HEADER_SIZE as u64);
+
         Self {
             buf,
-            cap: cap as usize,
+            cap,
             _ty: PhantomData,
         }
     }
+    ///
+    /// Records a copy of the queue into host-visible memory, whose length and items can be
+    /// read once the graph has been submitted and finished.
+    ///
+    pub fn readback(&self, device: &Arc<Device>, graph: &mut RenderGraph) -> HostWorkQueue<T> {
+        let queue = HostWorkQueue::new(device, self.cap);
 
+        let src = graph.bind_node(&self.buf);
+        let dst = graph.bind_node(&queue.buf);
+        graph.copy_buffer(src, dst);
+
+        queue
+    }
     pub fn buf(&self) -> &Arc<Buffer> {
         &self.buf
     }
     pub fn cap(&self) -> usize {
         self.cap
     }
+    ///
+    /// Records a pass that sets the length of the queue to zero on the GPU.
+    ///
+    pub fn reset(&self, graph: &mut RenderGraph) {
+        let buf = graph.bind_node(&self.buf);
+        graph.fill_buffer_region(buf, 0, LEN_RANGE);
+    }
+}
+
+///
+/// Copy of a `WorkQueue` in host-visible memory, see `WorkQueue::readback`.
+///
+#[derive(Debug)]
+pub struct HostWorkQueue<T> {
+    buf: Arc<Buffer>,
+    _ty: PhantomData<T>,
+}
+
+impl<T: Copy> HostWorkQueue<T> {
+    fn new(device: &Arc<Device>, cap: usize) -> Self {
+        let size = HEADER_SIZE + size_of::<T>() * cap;
+
+        let buf = Arc::new(
+            Buffer::create(
+                device,
+                BufferInfo::new_mappable(
+                    size as u64,
+                    vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                ),
+            )
+            .unwrap(),
+        );
+        Self {
+            buf,
+            _ty: PhantomData,
+        }
+    }
     pub fn len(&self) -> usize {
-        let slice = &Buffer::mapped_slice(&self.buf)[0..16];
+        let slice = &Buffer::mapped_slice(&self.buf)[0..HEADER_SIZE];
 
         let slice = bytemuck::cast_slice(slice);
 
-        let len: u32 = slice[1];
+        let len: u32 = slice[LEN_WORD as usize];
         len as usize
     }
     pub fn items(&self) -> &[T] {
         let slice = &Buffer::mapped_slice(&self.buf);
         let len = self.len();
 
-        unsafe { std::slice::from_raw_parts(slice[16..].as_ptr() as *const _, len as usize) }
-    }
-    pub fn clear(&mut self) {
-        let slice = Buffer::mapped_slice_mut(Arc::get_mut(&mut self.buf).unwrap());
-        let slice: &mut [u32] = bytemuck::cast_slice_mut(&mut slice[0..16]);
-        slice[1] = 0;
+        unsafe {
+            std::slice::from_raw_parts(slice[HEADER_SIZE..].as_ptr() as *const _, len as usize)
+        }
     }
 }
 
 pub type ItemWorkQueue<T> = WorkQueue<WorkItem<T>>;
+
+///
+/// Buffer of items that the `WorkQueueKernels` can operate on.
+///
+pub trait ItemBuffer<T> {
+    fn item_buf(&self) -> &Arc<Buffer>;
+    /// Maximum number of items, the actual number of a `WorkQueue` is only known on the GPU.
+    fn item_cap(&self) -> usize;
+    /// Whether the items are preceded by a `WorkQueue` header.
+    fn is_queue(&self) -> bool;
+}
+
+impl<T: Copy> ItemBuffer<T> for WorkQueue<T> {
+    fn item_buf(&self) -> &Arc<Buffer> {
+        &self.buf
+    }
+    fn item_cap(&self) -> usize {
+        self.cap
+    }
+    fn is_queue(&self) -> bool {
+        true
+    }
+}
+
+impl<T: Copy> ItemBuffer<T> for Array<T> {
+    fn item_buf(&self) -> &Arc<Buffer> {
+        &self.buf
+    }
+    fn item_cap(&self) -> usize {
+        self.count()
+    }
+    fn is_queue(&self) -> bool {
+        false
+    }
+}
+
+///
+/// Scan, compaction and partition primitives over `WorkQueue`s and `Array`s.
+/// Compaction and partition are stable, so they give a deterministic order of the items
+/// regardless of the order in which they were pushed.
+///
+pub struct WorkQueueKernels {
+    scan_blocks_ppl: CPipeline,
+    scan_add_block_sums_ppl: CPipeline,
+    partition_items_ppl: CPipeline,
+}
+
+impl WorkQueueKernels {
+    pub fn new(device: &Arc<Device>) -> anyhow::Result<Self> {
+        Ok(Self {
+            scan_blocks_ppl: CPipeline::new(device, "scan_blocks")?,
+            scan_add_block_sums_ppl: CPipeline::new(device, "scan_add_block_sums")?,
+            partition_items_ppl: CPipeline::new(device, "partition_items")?,
+        })
+    }
+    ///
+    /// Records an exclusive prefix sum of `values` into `scanned`.
+    ///
+    pub fn scan(
+        &self,
+        cache: &mut HashPool,
+        graph: &mut RenderGraph,
+        values: &Array<u32>,
+        scanned: &Array<u32>,
+    ) {
+        assert!(scanned.count() >= values.count());
+        let values_node = graph.bind_node(values.buf());
+        let scanned_node = graph.bind_node(scanned.buf());
+        self.scan_nodes(
+            cache,
+            graph,
+            values_node.into(),
+            scanned_node.into(),
+            values.count() as u32,
+        );
+    }
+    fn scan_nodes(
+        &self,
+        cache: &mut HashPool,
+        graph: &mut RenderGraph,
+        values: AnyBufferNode,
+        scanned: AnyBufferNode,
+        count: u32,
+    ) {
+        if count == 0 {
+            return;
+        }
+        let blocks = dispatch_size(count);
+        let block_sums = Self::lease_u32(cache, graph, blocks);
+
+        let pc = ScanPc { count };
+
+        graph
+            .begin_pass("Scan Blocks Pass")
+            .bind_pipeline(self.scan_blocks_ppl.ppl())
+            .read_descriptor((0, 0), values)
+            .write_descriptor((0, 1), scanned)
+            .write_descriptor((0, 2), block_sums)
+            .record_compute(move |comp, _| {
+                comp.push_constants(bytemuck::cast_slice(&[pc]));
+                comp.dispatch(blocks, 1, 1);
+            })
+            .submit_pass();
+
+        if blocks > 1 {
+            let scanned_block_sums = Self::lease_u32(cache, graph, blocks);
+            self.scan_nodes(
+                cache,
+                graph,
+                block_sums.into(),
+                scanned_block_sums.into(),
+                blocks,
+            );
+
+            graph
+                .begin_pass("Scan Add Block Sums Pass")
+                .bind_pipeline(self.scan_add_block_sums_ppl.ppl())
+                .write_descriptor((0, 0), scanned)
+                .read_descriptor((0, 1), scanned_block_sums)
+                .record_compute(move |comp, _| {
+                    comp.push_constants(bytemuck::cast_slice(&[pc]));
+                    comp.dispatch(blocks, 1, 1);
+                })
+                .submit_pass();
+        }
+    }
+    ///
+    /// Records a stable compaction of the items in `src` whose flag is 1 into `dst`.
+    /// `flags` holds a 0 or 1 for every item.
+    ///
+    pub fn compact<T: Copy>(
+        &self,
+        cache: &mut HashPool,
+        graph: &mut RenderGraph,
+        src: &impl ItemBuffer<T>,
+        flags: &Array<u32>,
+        dst: &impl ItemBuffer<T>,
+    ) {
+        self.partition_items(cache, graph, src, flags, dst, false);
+    }
+    ///
+    /// Records a stable partition of the items in `src` into `dst`, items whose flag is 1 come
+    /// before the ones whose flag is 0.
+    ///
+    pub fn partition<T: Copy>(
+        &self,
+        cache: &mut HashPool,
+        graph: &mut RenderGraph,
+        src: &impl ItemBuffer<T>,
+        flags: &Array<u32>,
+        dst: &impl ItemBuffer<T>,
+    ) {
+        self.partition_items(cache, graph, src, flags, dst, true);
+    }
+    fn partition_items<T: Copy>(
+        &self,
+        cache: &mut HashPool,
+        graph: &mut RenderGraph,
+        src: &impl ItemBuffer<T>,
+        flags: &Array<u32>,
+        dst: &impl ItemBuffer<T>,
+        keep_rejected: bool,
+    ) {
+        assert!(size_of::<T>() % size_of::<u32>() == 0);
+        assert!(flags.count() >= src.item_cap());
+        assert!(dst.item_cap() >= src.item_cap());
+
+        let cap = src.item_cap() as u32;
+
+        let flags_node = graph.bind_node(flags.buf());
+        let offsets = Self::lease_u32(cache, graph, cap.max(1));
+        self.scan_nodes(cache, graph, flags_node.into(), offsets.into(), cap);
+
+        let src_node = graph.bind_node(src.item_buf());
+        let dst_node = graph.bind_node(dst.item_buf());
+        if dst.is_queue() {
+            // The length is only written if there is at least one item
+            graph.fill_buffer_region(dst_node, 0, LEN_RANGE);
+        }
+
+        let pc = PartitionPc {
+            count: cap,
+            stride: (size_of::<T>() / size_of::<u32>()) as u32,
+            src_queue: src.is_queue() as u32,
+            dst_queue: dst.is_queue() as u32,
+            keep_rejected: keep_rejected as u32,
+        };
+
+        graph
+            .begin_pass("Partition Items Pass")
+            .bind_pipeline(self.partition_items_ppl.ppl())
+            .read_descriptor((0, 0), src_node)
+            .read_descriptor((0, 1), flags_node)
+            .read_descriptor((0, 2), offsets)
+            .write_descriptor((0, 3), dst_node)
+            .record_compute(move |comp, _| {
+                comp.push_constants(bytemuck::cast_slice(&[pc]));
+                comp.dispatch(dispatch_size(cap), 1, 1);
+            })
+            .submit_pass();
+    }
+    fn lease_u32(cache: &mut HashPool, graph: &mut RenderGraph, count: u32) -> BufferLeaseNode {
+        let buf = cache
+            .lease(BufferInfo::new(
+                (size_of::<u32>() * count as usize) as _,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+            ))
+            .unwrap();
+        graph.bind_node(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::rand::Rng;
+
+    /// Lengths around the workgroup size and ones that need several levels of block sums.
+    const LENGTHS: [usize; 9] = [1, 2, 63, 64, 65, 1000, 4096, 4097, 300_000];
+
+    struct Context {
+        device: Arc<Device>,
+        cache: HashPool,
+        kernels: WorkQueueKernels,
+    }
+
+    impl Context {
+        fn new() -> Self {
+            let device = Arc::new(Device::create_headless(DriverConfig::new().build()).unwrap());
+            let cache = HashPool::new(&device);
+            let kernels = WorkQueueKernels::new(&device).unwrap();
+            Self {
+                device,
+                cache,
+                kernels,
+            }
+        }
+        fn submit(&mut self, graph: RenderGraph) {
+            graph.resolve().submit(&mut self.cache, 0).unwrap();
+            unsafe { self.device.device_wait_idle().unwrap() };
+        }
+    }
+
+    fn random_flags(len: usize, seed: u32) -> Vec<u32> {
+        let mut rng = Rng::new(seed);
+        (0..len).map(|_| (rng.next_f32() < 0.5) as u32).collect()
+    }
+
+    fn items(len: usize) -> Vec<[u32; 3]> {
+        (0..len as u32).map(|i| [i, i * 7, !i]).collect()
+    }
+
+    fn exclusive_scan(values: &[u32]) -> Vec<u32> {
+        values
+            .iter()
+            .scan(0, |sum, v| {
+                let prev = *sum;
+                *sum += v;
+                Some(prev)
+            })
+            .collect()
+    }
+
+    fn partition(items: &[[u32; 3]], flags: &[u32], keep_rejected: bool) -> Vec<[u32; 3]> {
+        let accepted = items.iter().zip(flags).filter(|(_, f)| **f != 0);
+        let rejected = items.iter().zip(flags).filter(|(_, f)| **f == 0);
+        if keep_rejected {
+            accepted.chain(rejected).map(|(item, _)| *item).collect()
+        } else {
+            accepted.map(|(item, _)| *item).collect()
+        }
+    }
+
+    #[test]
+    fn scan_matches_cpu() {
+        let mut ctx = Context::new();
+        for (seed, len) in LENGTHS.into_iter().enumerate() {
+            let mut rng = Rng::new(seed as u32);
+            let values = (0..len).map(|_| rng.next_u32() % 16).collect::<Vec<_>>();
+
+            let mut graph = RenderGraph::new();
+            let values_arr = Array::storage(&ctx.device, &values);
+            let scanned = Array::<u32>::empty(&ctx.device, len);
+            ctx.kernels
+                .scan(&mut ctx.cache, &mut graph, &values_arr, &scanned);
+            ctx.submit(graph);
+
+            assert_eq!(scanned.map(), exclusive_scan(&values), "length {len}");
+        }
+    }
+
+    #[test]
+    fn compact_and_partition_match_cpu() {
+        let mut ctx = Context::new();
+        for (seed, len) in LENGTHS.into_iter().enumerate() {
+            let items = items(len);
+            let flags = random_flags(len, seed as u32);
+
+            for keep_rejected in [false, true] {
+                let mut graph = RenderGraph::new();
+                let src = Array::storage(&ctx.device, &items);
+                let flags_arr = Array::storage(&ctx.device, &flags);
+                let dst = WorkQueue::<[u32; 3]>::new(&ctx.device, &mut graph, len);
+                if keep_rejected {
+                    ctx.kernels
+                        .partition(&mut ctx.cache, &mut graph, &src, &flags_arr, &dst);
+                } else {
+                    ctx.kernels
+                        .compact(&mut ctx.cache, &mut graph, &src, &flags_arr, &dst);
+                }
+                let host = dst.readback(&ctx.device, &mut graph);
+                ctx.submit(graph);
+
+                let expected = partition(&items, &flags, keep_rejected);
+                assert_eq!(host.len(), expected.len(), "length {len}");
+                assert_eq!(host.items(), expected, "length {len}");
+            }
+        }
+    }
+
+    #[test]
+    fn compact_queue_into_queue() {
+        let mut ctx = Context::new();
+        for (seed, len) in LENGTHS.into_iter().enumerate() {
+            let items = items(len);
+            let all = vec![1; len];
+            let flags = random_flags(len, seed as u32);
+
+            let mut graph = RenderGraph::new();
+            let src = Array::storage(&ctx.device, &items);
+            let all_arr = Array::storage(&ctx.device, &all);
+            // Queues are twice as large as their content, so the length has to come from the
+            // header rather than the capacity
+            let queue = WorkQueue::<[u32; 3]>::new(&ctx.device, &mut graph, 2 * len);
+            let dst = WorkQueue::<[u32; 3]>::new(&ctx.device, &mut graph, 2 * len);
+            let flags_pad = Array::storage(&ctx.device, &[flags.clone(), all.clone()].concat());
+            ctx.kernels
+                .compact(&mut ctx.cache, &mut graph, &src, &all_arr, &queue);
+            ctx.kernels
+                .compact(&mut ctx.cache, &mut graph, &queue, &flags_pad, &dst);
+            let host = dst.readback(&ctx.device, &mut graph);
+            ctx.submit(graph);
+
+            assert_eq!(
+                host.items(),
+                partition(&items, &flags, false),
+                "length {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn compact_empty_queue() {
+        let mut ctx = Context::new();
+        let cap = 100;
+        let flags = vec![1; cap];
+
+        let mut graph = RenderGraph::new();
+        let src = WorkQueue::<[u32; 3]>::new(&ctx.device, &mut graph, cap);
+        let dst = WorkQueue::<[u32; 3]>::new(&ctx.device, &mut graph, cap);
+        let flags_arr = Array::storage(&ctx.device, &flags);
+        ctx.kernels
+            .compact(&mut ctx.cache, &mut graph, &src, &flags_arr, &dst);
+        ctx.kernels
+            .partition(&mut ctx.cache, &mut graph, &src, &flags_arr, &dst);
+        let host = dst.readback(&ctx.device, &mut graph);
+        ctx.submit(graph);
+
+        assert_eq!(host.len(), 0);
+        assert!(host.items().is_empty());
+    }
+}